@@ -1,5 +1,6 @@
 use thiserror::Error;
 use std::convert::Infallible;
+use std::fmt;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -9,12 +10,82 @@ pub enum Error {
     #[error("could not parse configuration")]
     TomlParse(#[from] toml::de::Error),
     #[cfg(feature = "toml")]
-    #[error("invalid key path")]
+    #[error("could not serialize configuration")]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[cfg(feature = "toml")]
+    #[error("invalid key path `{0}`")]
     InvalidPath(String),
-    #[error("invalid argument")]
+    #[cfg(feature = "toml")]
+    #[error("include cycle detected: {0}")]
+    IncludeCycle(String),
+    #[cfg(feature = "toml")]
+    #[error("templates are still expanding after {0} rounds")]
+    ResolutionLimit(usize),
+    #[cfg(feature = "toml")]
+    #[error("invalid template at `{0}`: {1}")]
+    InvalidTemplate(String, String),
+    #[cfg(feature = "toml")]
+    #[error(
+        "no configuration `{name}` in `{base_path}` for `{key}`, available are [{}]{}",
+        .available.join(", "),
+        .suggestion.as_ref().map(|x| format!(", did you mean `{}`?", x)).unwrap_or_default()
+    )]
+    UnknownChoice {
+        key: String,
+        name: String,
+        base_path: String,
+        available: Vec<String>,
+        suggestion: Option<String>,
+    },
+    #[cfg(feature = "toml")]
+    #[error("environment variable `{0}` required by `{1}` is not set")]
+    MissingEnv(String, String),
+    #[cfg(feature = "toml")]
+    #[error("command `{0}` failed: {1}")]
+    CommandFailed(String, String),
+    #[cfg(feature = "toml")]
+    #[error("`{0}` violates the template policy: {1}")]
+    PolicyViolation(String, String),
+    #[cfg(feature = "toml")]
+    #[error("reference cycle detected: {0}")]
+    ReferenceCycle(String),
+    #[cfg(feature = "toml")]
+    #[error("reference `${{{0}}}` in `{1}` does not exist")]
+    UnknownReference(String, String),
+    #[cfg(feature = "toml")]
+    #[error("invalid reference at `{0}`: {1}")]
+    InvalidReference(String, String),
+    #[cfg(feature = "toml")]
+    #[error("invalid expression at `{0}`: {1}")]
+    InvalidExpression(String, String),
+    #[cfg(feature = "toml")]
+    #[error("could not read git repository: {0}")]
+    Git(String),
+    #[cfg(feature = "toml")]
+    #[error("invalid run directory: {0}")]
+    InvalidRunDir(String),
+    #[cfg(feature = "toml")]
+    #[error("invalid lock file {0}")]
+    InvalidLock(String),
+    #[cfg(feature = "toml")]
+    #[error("can not {0}: {1}")]
+    InvalidOverride(String, String),
+    #[cfg(feature = "toml")]
+    #[error("unknown merge strategy `{0}`, expected replace, deep, append, prepend, union or merge_by=<key>")]
+    InvalidStrategy(String),
+    #[cfg(feature = "toml")]
+    #[error("type mismatch at `{0}`: can not override {1} with {2}")]
+    TypeMismatch(String, &'static str, &'static str),
+    #[cfg(feature = "toml")]
+    #[error("the configuration is not applied with --plan, applying it takes these steps:\n{0}")]
+    PlanRequested(crate::Plan),
+    #[cfg(feature = "toml")]
+    #[error("lock file has no recorded output of template `{1}` at `{0}`")]
+    MissingLockEntry(String, String),
+    #[error("invalid argument `{0}`, expected `key=value`")]
     InvalidArg(String),
-    #[error("merging dictionaries failed")]
-    MergeFailed,
+    #[error("could not apply overrides{}", .0.iter().map(|x| format!("\n  {}", x)).collect::<String>())]
+    MergeFailed(Vec<Unapplied>),
     #[error("key {0} does not exist in {1}")]
     KeyNotExists(String, String),
     #[error("parameter violates constrain: {0}")]
@@ -26,6 +97,46 @@ pub enum Error {
     },
 }
 
+/// An override which could not be applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unapplied {
+    /// the override, like `modify model.hidden = 32`
+    pub action: String,
+    /// where the override comes from, like `argument 2 `model.hidden=32``
+    pub origin: String,
+    pub path: String,
+    /// the value the override tried to set
+    pub value: Option<String>,
+    /// type and value found at the path
+    pub existing: Option<(String, String)>,
+    /// why the override could not be applied, e.g. a missing key or a type mismatch
+    pub reason: String,
+    /// an existing key close to the path
+    pub suggestion: Option<String>,
+}
+
+impl Unapplied {
+    /// The reason with the value found at the path and the suggestion
+    pub fn details(&self) -> String {
+        let mut details = self.reason.clone();
+
+        if let Some((kind, value)) = &self.existing {
+            details.push_str(&format!(", found {} {}", kind, value));
+        }
+        if let Some(suggestion) = &self.suggestion {
+            details.push_str(&format!(", did you mean `{}`?", suggestion));
+        }
+
+        details
+    }
+}
+
+impl fmt::Display for Unapplied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.action, self.origin, self.details())
+    }
+}
+
 impl From<Infallible> for Error {
     fn from(_: Infallible) -> Self {
         unreachable!()
@@ -0,0 +1,419 @@
+//! Small side-effect free expression language used by the `expr` template
+//!
+//! Supports integer and float arithmetic (`+ - * / %`), comparisons, boolean
+//! logic (`&& || !`), string literals and concatenation with `+`, references
+//! `${path.to.key}` and the functions `min`, `max`, `abs`, `round`, `int`,
+//! `float` and `str`.
+
+use toml::Value;
+
+use crate::{Result, Error};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(Value),
+    Reference(String),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+const OPS: [&str; 16] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")",
+];
+
+fn tokenize(key: &str, expr: &str) -> Result<Vec<Token>> {
+    let invalid = |msg: String| Error::InvalidExpression(key.into(), msg);
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+
+        if let Some(inner) = rest.strip_prefix("${") {
+            let end = inner.find('}')
+                .ok_or_else(|| invalid(format!("unterminated reference in `{}`", expr)))?;
+
+            tokens.push(Token::Reference(inner[..end].trim().to_string()));
+            rest = &inner[end + 1..];
+        } else if c == '\'' || c == '"' {
+            let end = rest[1..].find(c)
+                .ok_or_else(|| invalid(format!("unterminated string in `{}`", expr)))?;
+
+            tokens.push(Token::Literal(Value::String(rest[1..end + 1].to_string())));
+            rest = &rest[end + 2..];
+        } else if c.is_ascii_digit() || c == '.' {
+            // a sign directly after the exponent marker is part of the number, as in `1e-5`
+            let mut prev = ' ';
+            let end = rest.find(|x: char| {
+                let sign = (x == '-' || x == '+') && (prev == 'e' || prev == 'E');
+                prev = x;
+
+                !(x.is_ascii_alphanumeric() || x == '.' || x == '_' || sign)
+            }).unwrap_or(rest.len());
+            let num = rest[..end].replace('_', "");
+
+            let val = match num.parse::<i64>() {
+                Ok(i) => Value::Integer(i),
+                Err(_) => Value::Float(num.parse()
+                    .map_err(|_| invalid(format!("invalid number `{}`", &rest[..end])))?),
+            };
+
+            tokens.push(Token::Literal(val));
+            rest = &rest[end..];
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest.find(|x: char| !(x.is_alphanumeric() || x == '_'))
+                .unwrap_or(rest.len());
+
+            tokens.push(match &rest[..end] {
+                "true" => Token::Literal(Value::Boolean(true)),
+                "false" => Token::Literal(Value::Boolean(false)),
+                ident => Token::Ident(ident.to_string()),
+            });
+            rest = &rest[end..];
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            rest = &rest[1..];
+        } else {
+            let op = OPS.iter().find(|x| rest.starts_with(*x))
+                .ok_or_else(|| invalid(format!("unexpected character `{}` in `{}`", c, expr)))?;
+
+            tokens.push(match *op {
+                "(" => Token::LParen,
+                ")" => Token::RParen,
+                op => Token::Op(op),
+            });
+            rest = &rest[op.len()..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Reference(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// Binding power of binary operators, higher binds stronger
+fn precedence(op: &str) -> u8 {
+    match op {
+        "||" => 1,
+        "&&" => 2,
+        "==" | "!=" => 3,
+        "<" | "<=" | ">" | ">=" => 4,
+        "+" | "-" => 5,
+        "*" | "/" | "%" => 6,
+        _ => 0,
+    }
+}
+
+struct Parser<'a> {
+    key: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn invalid(&self, msg: &str) -> Error {
+        Error::InvalidExpression(self.key.into(), msg.into())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+
+        token
+    }
+
+    fn expect(&mut self, token: Token) -> Result<()> {
+        match self.next() {
+            Some(x) if x == token => Ok(()),
+            _ => Err(self.invalid(&format!("expected {:?}", token))),
+        }
+    }
+
+    fn parse(&mut self, min_prec: u8) -> Result<Expr> {
+        let mut lhs = match self.next() {
+            Some(Token::Literal(val)) => Expr::Literal(val),
+            Some(Token::Reference(path)) => Expr::Reference(path),
+            Some(Token::Op(op @ ("-" | "!"))) => Expr::Unary(op, Box::new(self.parse(7)?)),
+            Some(Token::LParen) => {
+                let inner = self.parse(0)?;
+                self.expect(Token::RParen)?;
+
+                inner
+            },
+            Some(Token::Ident(name)) => {
+                self.expect(Token::LParen)?;
+
+                let mut args = Vec::new();
+                if self.tokens.get(self.pos) == Some(&Token::RParen) {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        args.push(self.parse(0)?);
+
+                        match self.next() {
+                            Some(Token::Comma) => continue,
+                            Some(Token::RParen) => break,
+                            _ => return Err(self.invalid("expected `,` or `)` in function call")),
+                        }
+                    }
+                }
+
+                Expr::Call(name, args)
+            },
+            Some(x) => return Err(self.invalid(&format!("unexpected {:?}", x))),
+            None => return Err(self.invalid("unexpected end of expression")),
+        };
+
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos).cloned() {
+            let prec = precedence(op);
+            if prec == 0 || prec <= min_prec {
+                break;
+            }
+
+            self.pos += 1;
+            let rhs = self.parse(prec)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+}
+
+/// Evaluate an expression, references are looked up with `lookup`
+pub(crate) fn evaluate(key: &str, expr: &str, lookup: &mut dyn FnMut(&str) -> Result<Value>) -> Result<Value> {
+    let mut parser = Parser { key, tokens: tokenize(key, expr)?, pos: 0 };
+    let ast = parser.parse(0)?;
+
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.invalid(&format!("unexpected {:?}", parser.tokens[parser.pos])));
+    }
+
+    Evaluator { key, lookup }.eval(&ast)
+}
+
+struct Evaluator<'a> {
+    key: &'a str,
+    lookup: &'a mut dyn FnMut(&str) -> Result<Value>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn mismatch(&self, op: &str, a: &Value, b: Option<&Value>) -> Error {
+        let msg = match b {
+            Some(b) => format!("cannot apply `{}` to {} and {}", op, a.type_str(), b.type_str()),
+            None => format!("cannot apply `{}` to {}", op, a.type_str()),
+        };
+
+        Error::InvalidExpression(self.key.into(), msg)
+    }
+
+    fn overflow(&self, op: &str) -> Error {
+        Error::InvalidExpression(self.key.into(), format!("integer overflow in `{}`", op))
+    }
+
+    /// Convert an already rounded float, failing for infinity, NaN and values outside of i64
+    fn integer(&self, op: &str, f: f64) -> Result<Value> {
+        // 2^63 is exactly representable, i64::MAX is not; NaN and infinity are outside too
+        if (-9_223_372_036_854_775_808.0..9_223_372_036_854_775_808.0).contains(&f) {
+            Ok(Value::Integer(f as i64))
+        } else {
+            Err(Error::InvalidExpression(self.key.into(), format!("cannot convert {} to integer in `{}`", f, op)))
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value> {
+        match expr {
+            Expr::Literal(val) => Ok(val.clone()),
+            Expr::Reference(path) => (self.lookup)(path),
+            Expr::Unary(op, inner) => match (*op, self.eval(inner)?) {
+                ("-", Value::Integer(i)) => i.checked_neg().map(Value::Integer).ok_or_else(|| self.overflow(op)),
+                ("-", Value::Float(f)) => Ok(Value::Float(-f)),
+                ("!", Value::Boolean(b)) => Ok(Value::Boolean(!b)),
+                (op, x) => Err(self.mismatch(op, &x, None)),
+            },
+            Expr::Binary(op @ ("&&" | "||"), a, b) => {
+                let a = match self.eval(a)? {
+                    Value::Boolean(a) => a,
+                    x => return Err(self.mismatch(op, &x, None)),
+                };
+
+                // short-circuit evaluation
+                if (*op == "&&" && !a) || (*op == "||" && a) {
+                    return Ok(Value::Boolean(a));
+                }
+
+                match self.eval(b)? {
+                    Value::Boolean(b) => Ok(Value::Boolean(b)),
+                    x => Err(self.mismatch(op, &x, None)),
+                }
+            },
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.eval(a)?, self.eval(b)?);
+                self.binary(op, a, b)
+            },
+            Expr::Call(name, args) => {
+                let args = args.iter().map(|x| self.eval(x)).collect::<Result<Vec<_>>>()?;
+                self.call(name, args)
+            },
+        }
+    }
+
+    fn binary(&self, op: &str, a: Value, b: Value) -> Result<Value> {
+        use Value::*;
+
+        let res = match (op, &a, &b) {
+            ("+", String(x), String(y)) => String(format!("{}{}", x, y)),
+            ("==", _, _) | ("!=", _, _) => {
+                let equal = match (&a, &b) {
+                    (Integer(x), Float(y)) | (Float(y), Integer(x)) => *x as f64 == *y,
+                    (x, y) if x.same_type(y) => x == y,
+                    _ => return Err(self.mismatch(op, &a, Some(&b))),
+                };
+
+                Boolean(equal == (op == "=="))
+            },
+            ("<" | "<=" | ">" | ">=", String(x), String(y)) => Boolean(compare(op, x.cmp(y))),
+            (_, Integer(x), Integer(y)) => {
+                let res = match op {
+                    "+" => x.checked_add(*y),
+                    "-" => x.checked_sub(*y),
+                    "*" => x.checked_mul(*y),
+                    "/" | "%" if *y == 0 => return Err(Error::InvalidExpression(self.key.into(), "division by zero".into())),
+                    "/" => x.checked_div(*y),
+                    "%" => x.checked_rem(*y),
+                    op => return Ok(Boolean(compare(op, x.cmp(y)))),
+                };
+
+                Integer(res.ok_or_else(|| self.overflow(op))?)
+            },
+            (_, Integer(_) | Float(_), Integer(_) | Float(_)) => {
+                let (x, y) = (as_float(&a), as_float(&b));
+
+                match op {
+                    "+" => Float(x + y),
+                    "-" => Float(x - y),
+                    "*" => Float(x * y),
+                    "/" => Float(x / y),
+                    "%" => Float(x % y),
+                    op => match x.partial_cmp(&y) {
+                        Some(ord) => Boolean(compare(op, ord)),
+                        None => Boolean(false),
+                    },
+                }
+            },
+            _ => return Err(self.mismatch(op, &a, Some(&b))),
+        };
+
+        Ok(res)
+    }
+
+    fn call(&self, name: &str, args: Vec<Value>) -> Result<Value> {
+        let invalid = |msg: String| Error::InvalidExpression(self.key.into(), msg);
+
+        match (name, args.as_slice()) {
+            ("min" | "max", [first, rest @ ..]) => {
+                let mut best = first.clone();
+                for arg in rest {
+                    let op = if name == "min" { "<" } else { ">" };
+                    if let Value::Boolean(true) = self.binary(op, arg.clone(), best.clone())? {
+                        best = arg.clone();
+                    }
+                }
+
+                Ok(best)
+            },
+            ("abs", [Value::Integer(i)]) => i.checked_abs().map(Value::Integer).ok_or_else(|| self.overflow(name)),
+            ("abs", [Value::Float(f)]) => Ok(Value::Float(f.abs())),
+            ("round", [Value::Float(f)]) => self.integer(name, f.round()),
+            ("round" | "int", [Value::Integer(i)]) => Ok(Value::Integer(*i)),
+            ("int", [Value::Float(f)]) => self.integer(name, f.trunc()),
+            ("int", [Value::String(s)]) => s.trim().parse().map(Value::Integer)
+                .map_err(|_| invalid(format!("cannot convert \"{}\" to integer", s))),
+            ("float", [Value::Integer(i)]) => Ok(Value::Float(*i as f64)),
+            ("float", [Value::Float(f)]) => Ok(Value::Float(*f)),
+            ("float", [Value::String(s)]) => s.trim().parse().map(Value::Float)
+                .map_err(|_| invalid(format!("cannot convert \"{}\" to float", s))),
+            ("str", [Value::String(s)]) => Ok(Value::String(s.clone())),
+            ("str", [x]) => Ok(Value::String(x.to_string())),
+            ("min" | "max" | "abs" | "round" | "int" | "float" | "str", args) => Err(invalid(format!(
+                "invalid arguments for `{}`: ({})",
+                name,
+                args.iter().map(|x| x.type_str()).collect::<Vec<_>>().join(", ")
+            ))),
+            _ => Err(invalid(format!("unknown function `{}`", name))),
+        }
+    }
+}
+
+fn as_float(val: &Value) -> f64 {
+    match val {
+        Value::Integer(i) => *i as f64,
+        Value::Float(f) => *f,
+        _ => unreachable!(),
+    }
+}
+
+fn compare(op: &str, ord: std::cmp::Ordering) -> bool {
+    use std::cmp::Ordering::*;
+
+    matches!((op, ord), ("<", Less) | ("<=", Less | Equal) | (">", Greater) | (">=", Greater | Equal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<Value> {
+        evaluate("test", expr, &mut |path| match path {
+            "model.d_model" => Ok(Value::Integer(256)),
+            "total_steps" => Ok(Value::Integer(1000)),
+            "variant" => Ok(Value::String("TrainModel".into())),
+            _ => Err(Error::UnknownReference(path.into(), "test".into())),
+        })
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(eval("4 * ${model.d_model}").unwrap(), Value::Integer(1024));
+        assert_eq!(eval("int(0.1 * ${total_steps})").unwrap(), Value::Integer(100));
+        assert_eq!(eval("-(1 + 2) * 3 - 7 % 4").unwrap(), Value::Integer(-12));
+        assert_eq!(eval("max(1, 2.5, 2)").unwrap(), Value::Float(2.5));
+        assert_eq!(eval("1e-5 * 2").unwrap(), Value::Float(2e-5));
+        assert_eq!(eval("2E+2-1").unwrap(), Value::Float(199.0));
+        assert_eq!(eval("'run-' + str(${total_steps})").unwrap(), Value::String("run-1000".into()));
+    }
+
+    #[test]
+    fn test_logic() {
+        assert_eq!(eval("${variant} == 'TrainModel' && 2 >= 1.5").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("!(1 < 2) || false").unwrap(), Value::Boolean(false));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(eval("${unknown} + 1"), Err(Error::UnknownReference(..))));
+        assert!(matches!(eval("'a' * 2"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("1 / 0"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("9223372036854775807 + 1"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("-(-9223372036854775807 - 1)"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("abs(-9223372036854775807 - 1)"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("(-9223372036854775807 - 1) / -1"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("int(1e300)"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("round(-1e300 * 1e300)"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("int(0.0 / 0.0)"), Err(Error::InvalidExpression(..))));
+        assert!(matches!(eval("int(9223372036854775807.0)"), Err(Error::InvalidExpression(..))));
+        assert_eq!(eval("int(-9223372036854775808.0)").unwrap(), Value::Integer(i64::MIN));
+        assert!(matches!(eval("(1 + 2"), Err(Error::InvalidExpression(..))));
+    }
+}
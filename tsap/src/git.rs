@@ -0,0 +1,923 @@
+//! Read metadata of a git repository directly from the `.git` directory
+//!
+//! Only reading is supported: resolving `HEAD` and references, loose and
+//! packed objects, and the index. This is enough to report the current
+//! revision, branch, whether the work tree is dirty and a description based on
+//! the closest tag, without requiring a `git` binary.
+
+use std::cell::RefCell;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use flate2::read::ZlibDecoder;
+
+use crate::{Result, Error};
+
+/// Binary id of an object
+type Oid = [u8; 20];
+
+/// Longest chain of deltas followed to reconstruct an object, git itself creates at most 4095
+const MAX_DELTA_DEPTH: usize = 4095;
+
+/// Base of a delta in a pack
+#[derive(Clone, Copy)]
+enum Base<'a> {
+    Offset(&'a PackIndex, u64),
+    Id(Oid),
+}
+
+fn invalid<T: std::fmt::Display>(msg: T) -> Error {
+    Error::Git(msg.to_string())
+}
+
+/// Hex encoding of an object id
+fn hex(id: &[u8]) -> String {
+    id.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Parse a hex encoded object id
+fn unhex(id: &str) -> Result<Oid> {
+    let id = id.trim();
+    if id.len() != 40 {
+        return Err(invalid(format!("invalid object id `{}`", id)));
+    }
+
+    let mut out = [0u8; 20];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = id.get(2 * i..2 * i + 2)
+            .and_then(|x| u8::from_str_radix(x, 16).ok())
+            .ok_or_else(|| invalid(format!("invalid object id `{}`", id)))?;
+    }
+
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl Kind {
+    fn from_name(name: &str) -> Result<Kind> {
+        match name {
+            "commit" => Ok(Kind::Commit),
+            "tree" => Ok(Kind::Tree),
+            "blob" => Ok(Kind::Blob),
+            "tag" => Ok(Kind::Tag),
+            x => Err(invalid(format!("unknown object type `{}`", x))),
+        }
+    }
+
+    fn from_pack(typ: u8) -> Result<Kind> {
+        match typ {
+            1 => Ok(Kind::Commit),
+            2 => Ok(Kind::Tree),
+            3 => Ok(Kind::Blob),
+            4 => Ok(Kind::Tag),
+            x => Err(invalid(format!("unknown packed object type {}", x))),
+        }
+    }
+}
+
+/// Index of a pack file, maps object ids to offsets in the pack
+struct PackIndex {
+    pack: PathBuf,
+    ids: Vec<Oid>,
+    offsets: Vec<u64>,
+    /// pack file, opened on first use
+    file: RefCell<Option<File>>,
+}
+
+impl PackIndex {
+    fn open(path: &Path) -> Result<PackIndex> {
+        let data = fs::read(path)?;
+        let truncated = || invalid(format!("truncated pack index {}", path.display()));
+        let u32_at = |pos: usize| -> Result<u32> {
+            data.get(pos..pos + 4)
+                .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+                .ok_or_else(truncated)
+        };
+
+        if data.len() < 8 + 256 * 4 || data[..4] != [0xff, b't', b'O', b'c'] || u32_at(4)? != 2 {
+            return Err(invalid(format!("unsupported pack index {}", path.display())));
+        }
+
+        let num = u32_at(8 + 255 * 4)? as usize;
+        let ids_start = 8 + 256 * 4;
+        let offsets_start = ids_start + num * 20 + num * 4;
+        let large_start = offsets_start + num * 4;
+
+        if data.len() < large_start {
+            return Err(truncated());
+        }
+
+        let ids = (0..num)
+            .map(|i| {
+                let mut id = [0u8; 20];
+                id.copy_from_slice(&data[ids_start + i * 20..ids_start + (i + 1) * 20]);
+                id
+            })
+            .collect();
+
+        let offsets = (0..num)
+            .map(|i| {
+                let offset = u32_at(offsets_start + i * 4)?;
+                if offset & 0x8000_0000 == 0 {
+                    Ok(offset as u64)
+                } else {
+                    let pos = large_start + (offset & 0x7fff_ffff) as usize * 8;
+                    data.get(pos..pos + 8)
+                        .map(|x| u64::from_be_bytes(x.try_into().unwrap()))
+                        .ok_or_else(truncated)
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(PackIndex { pack: path.with_extension("pack"), ids, offsets, file: RefCell::new(None) })
+    }
+
+    fn find(&self, id: &Oid) -> Option<u64> {
+        self.ids.binary_search(id).ok().map(|x| self.offsets[x])
+    }
+}
+
+/// Read a variable length size with 7 bits per byte, least significant first
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut val = 0usize;
+    let mut shift = 0;
+
+    loop {
+        let byte = *data.get(*pos).ok_or_else(|| invalid("truncated delta"))?;
+        if shift >= usize::BITS {
+            return Err(invalid("invalid size in delta"));
+        }
+        *pos += 1;
+        val |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(val);
+        }
+    }
+}
+
+/// Reconstruct an object from its base and a delta
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    let _base_len = read_varint(delta, &mut pos)?;
+    let out_len = read_varint(delta, &mut pos)?;
+    // the size is only a hint, do not trust it for large allocations
+    let mut out = Vec::with_capacity(out_len.min(1 << 20));
+    let next = |pos: &mut usize| -> Result<usize> {
+        let byte = *delta.get(*pos).ok_or_else(|| invalid("truncated delta"))?;
+        *pos += 1;
+
+        Ok(byte as usize)
+    };
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+
+        if op & 0x80 != 0 {
+            // copy a range of the base object
+            let mut offset = 0usize;
+            let mut size = 0usize;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    offset |= next(&mut pos)? << (8 * i);
+                }
+            }
+            for i in 0..3 {
+                if op & (0x10 << i) != 0 {
+                    size |= next(&mut pos)? << (8 * i);
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+
+            let range = base.get(offset..offset + size).ok_or_else(|| invalid("delta exceeds its base"))?;
+            out.extend_from_slice(range);
+        } else if op != 0 {
+            // insert literal data
+            let size = op as usize;
+            out.extend_from_slice(delta.get(pos..pos + size).ok_or_else(|| invalid("truncated delta"))?);
+            pos += size;
+        } else {
+            return Err(invalid("invalid delta instruction"));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Entry of the index, the staging area of git
+struct IndexEntry {
+    path: String,
+    id: Oid,
+    mode: u32,
+    size: u32,
+    mtime: (u32, u32),
+    stage: u16,
+    skip_worktree: bool,
+    intent_to_add: bool,
+}
+
+/// Parent ids, committer time and tree of a commit
+type Commit = (Vec<Oid>, i64, Option<Oid>);
+
+/// A git repository found on disk
+pub(crate) struct Repository {
+    git_dir: PathBuf,
+    common_dir: PathBuf,
+    work_dir: PathBuf,
+    packs: Vec<PackIndex>,
+    /// commits already read, history walks visit them several times
+    commits: RefCell<HashMap<Oid, Commit>>,
+}
+
+impl Repository {
+    /// Find the repository containing `start` by walking up the directories
+    pub(crate) fn discover(start: &Path) -> Result<Repository> {
+        let start = start.canonicalize()?;
+
+        for dir in start.ancestors() {
+            let dot_git = dir.join(".git");
+
+            let git_dir = if dot_git.is_dir() {
+                dot_git
+            } else if dot_git.is_file() {
+                // worktrees and submodules point to the actual git directory
+                let content = fs::read_to_string(&dot_git)?;
+                let target = content.trim().strip_prefix("gitdir:")
+                    .ok_or_else(|| invalid(format!("invalid {}", dot_git.display())))?
+                    .trim();
+
+                dir.join(target)
+            } else {
+                continue;
+            };
+
+            let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+                Ok(common) => git_dir.join(common.trim()),
+                Err(_) => git_dir.clone(),
+            };
+
+            let mut packs = Vec::new();
+            if let Ok(entries) = fs::read_dir(common_dir.join("objects").join("pack")) {
+                for entry in entries.filter_map(|x| x.ok()) {
+                    if entry.path().extension().map(|x| x == "idx").unwrap_or(false) {
+                        packs.push(PackIndex::open(&entry.path())?);
+                    }
+                }
+            }
+
+            return Ok(Repository {
+                git_dir,
+                common_dir,
+                work_dir: dir.to_path_buf(),
+                packs,
+                commits: RefCell::new(HashMap::new()),
+            });
+        }
+
+        Err(invalid(format!("{} is not inside a git repository", start.display())))
+    }
+
+    pub(crate) fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// Resolve a reference like `refs/heads/main` to an object id
+    fn resolve_ref(&self, name: &str) -> Result<Option<String>> {
+        for dir in [&self.git_dir, &self.common_dir] {
+            if let Ok(content) = fs::read_to_string(dir.join(name)) {
+                let content = content.trim();
+
+                return match content.strip_prefix("ref:") {
+                    Some(target) => self.resolve_ref(target.trim()),
+                    None => Ok(Some(content.to_string())),
+                };
+            }
+        }
+
+        Ok(self.packed_refs()?.into_iter()
+            .find(|(x, _, _)| x == name)
+            .map(|(_, id, _)| id))
+    }
+
+    /// Read `packed-refs` as list of (name, id, peeled id)
+    fn packed_refs(&self) -> Result<Vec<(String, String, Option<String>)>> {
+        let content = match fs::read_to_string(self.common_dir.join("packed-refs")) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut refs: Vec<(String, String, Option<String>)> = Vec::new();
+        for line in content.lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+
+            if let Some(peeled) = line.strip_prefix('^') {
+                if let Some(last) = refs.last_mut() {
+                    last.2 = Some(peeled.trim().to_string());
+                }
+            } else if let Some((id, name)) = line.split_once(' ') {
+                refs.push((name.trim().to_string(), id.to_string(), None));
+            }
+        }
+
+        Ok(refs)
+    }
+
+    /// Branch name and commit id of `HEAD`, either is missing for a detached
+    /// head or a branch without commits
+    pub(crate) fn head(&self) -> Result<(Option<String>, Option<String>)> {
+        let head = fs::read_to_string(self.git_dir.join("HEAD"))?;
+        let head = head.trim();
+
+        match head.strip_prefix("ref:") {
+            Some(target) => {
+                let target = target.trim();
+                let branch = target.strip_prefix("refs/heads/").unwrap_or(target).to_string();
+
+                Ok((Some(branch), self.resolve_ref(target)?))
+            },
+            None => Ok((None, Some(head.to_string()))),
+        }
+    }
+
+    /// Commit id of `HEAD`
+    pub(crate) fn rev(&self) -> Result<String> {
+        self.head()?.1.ok_or_else(|| invalid("HEAD does not point to a commit yet"))
+    }
+
+    fn read_object(&self, id: &Oid) -> Result<(Kind, Vec<u8>)> {
+        // follow the chain of deltas down to the base object, then apply them in reverse
+        let mut deltas = Vec::new();
+        let mut next = Base::Id(*id);
+
+        let (kind, mut data) = loop {
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(invalid(format!("delta chain of {} is longer than {}", hex(id), MAX_DELTA_DEPTH)));
+            }
+
+            let (pack, offset) = match next {
+                Base::Offset(pack, offset) => (pack, offset),
+                Base::Id(id) => {
+                    if let Some(object) = self.read_loose(&id)? {
+                        break object;
+                    }
+
+                    self.packs.iter()
+                        .find_map(|pack| pack.find(&id).map(|offset| (pack, offset)))
+                        .ok_or_else(|| invalid(format!("object {} not found", hex(&id))))?
+                },
+            };
+
+            match Self::read_packed(pack, offset)? {
+                (typ, None, data) => break (Kind::from_pack(typ)?, data),
+                (_, Some(base), delta) => {
+                    deltas.push(delta);
+                    next = base;
+                },
+            }
+        };
+
+        for delta in deltas.iter().rev() {
+            data = apply_delta(&data, delta)?;
+        }
+
+        Ok((kind, data))
+    }
+
+    fn read_loose(&self, id: &Oid) -> Result<Option<(Kind, Vec<u8>)>> {
+        let hex_id = hex(id);
+        let loose = self.common_dir.join("objects").join(&hex_id[..2]).join(&hex_id[2..]);
+        if !loose.is_file() {
+            return Ok(None);
+        }
+
+        let mut data = Vec::new();
+        ZlibDecoder::new(File::open(loose)?).read_to_end(&mut data)?;
+
+        let header_end = data.iter().position(|x| *x == 0)
+            .ok_or_else(|| invalid(format!("invalid object {}", hex_id)))?;
+        let header = String::from_utf8_lossy(&data[..header_end]).to_string();
+        let kind = Kind::from_name(header.split(' ').next().unwrap_or(""))?;
+
+        Ok(Some((kind, data[header_end + 1..].to_vec())))
+    }
+
+    /// Read the entry at `offset` of a pack, the type, the base of a delta and the data
+    fn read_packed(pack: &PackIndex, offset: u64) -> Result<(u8, Option<Base<'_>>, Vec<u8>)> {
+        let truncated = || invalid(format!("truncated object in {}", pack.pack.display()));
+
+        // the header is at most a few bytes, the compressed data follows
+        let mut header = [0u8; 32];
+        let read = {
+            let mut file = pack.file.borrow_mut();
+            let f = match &mut *file {
+                Some(f) => f,
+                None => file.insert(File::open(&pack.pack)?),
+            };
+
+            f.seek(SeekFrom::Start(offset))?;
+            f.read(&mut header)?
+        };
+        let header = &header[..read];
+        let byte_at = |pos: usize| header.get(pos).copied().ok_or_else(truncated);
+
+        let first = byte_at(0)?;
+        let typ = (first >> 4) & 0x7;
+        let mut size = (first & 0x0f) as usize;
+        let mut pos = 1;
+        let mut shift = 4;
+        let mut byte = first;
+        while byte & 0x80 != 0 {
+            byte = byte_at(pos)?;
+            if shift >= usize::BITS {
+                return Err(invalid(format!("invalid object size in {}", pack.pack.display())));
+            }
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            pos += 1;
+        }
+
+        let base = match typ {
+            6 => {
+                // base is at a relative offset before the object in the same pack
+                let mut byte = byte_at(pos)?;
+                let mut rel = (byte & 0x7f) as u64;
+                pos += 1;
+                while byte & 0x80 != 0 {
+                    byte = byte_at(pos)?;
+                    rel = rel.checked_add(1)
+                        .and_then(|x| x.checked_mul(1 << 7))
+                        .ok_or_else(truncated)?
+                        | (byte & 0x7f) as u64;
+                    pos += 1;
+                }
+
+                let base_offset = offset.checked_sub(rel)
+                    .filter(|_| rel > 0)
+                    .ok_or_else(|| invalid(format!("invalid delta base in {}", pack.pack.display())))?;
+
+                Some(Base::Offset(pack, base_offset))
+            },
+            7 => {
+                let mut base_id = [0u8; 20];
+                base_id.copy_from_slice(header.get(pos..pos + 20).ok_or_else(truncated)?);
+                pos += 20;
+
+                Some(Base::Id(base_id))
+            },
+            _ => None,
+        };
+
+        let mut file = pack.file.borrow_mut();
+        let f = file.as_mut().unwrap();
+        f.seek(SeekFrom::Start(offset + pos as u64))?;
+        // the size is only a hint, do not trust it for large allocations
+        let mut data = Vec::with_capacity(size.min(1 << 20));
+        ZlibDecoder::new(f).take(size as u64).read_to_end(&mut data)?;
+
+        Ok((typ, base, data))
+    }
+
+    /// Parent ids, committer time and tree of a commit
+    fn commit(&self, id: &Oid) -> Result<Commit> {
+        if let Some(commit) = self.commits.borrow().get(id) {
+            return Ok(commit.clone());
+        }
+
+        let (kind, data) = self.read_object(id)?;
+        if kind != Kind::Commit {
+            return Err(invalid(format!("{} is not a commit", hex(id))));
+        }
+
+        let mut parents = Vec::new();
+        let mut time = 0;
+        let mut tree = None;
+        for line in String::from_utf8_lossy(&data).lines() {
+            if line.is_empty() {
+                break;
+            } else if let Some(parent) = line.strip_prefix("parent ") {
+                parents.push(unhex(parent)?);
+            } else if let Some(t) = line.strip_prefix("tree ") {
+                tree = Some(unhex(t)?);
+            } else if let Some(committer) = line.strip_prefix("committer ") {
+                time = committer.rsplit(' ').nth(1).and_then(|x| x.parse().ok()).unwrap_or(0);
+            }
+        }
+
+        let commit = (parents, time, tree);
+        self.commits.borrow_mut().insert(*id, commit.clone());
+
+        Ok(commit)
+    }
+
+    /// Follow annotated tags to the commit they point to
+    fn peel(&self, id: Oid) -> Result<Oid> {
+        let (kind, data) = self.read_object(&id)?;
+        if kind != Kind::Tag {
+            return Ok(id);
+        }
+
+        let target = String::from_utf8_lossy(&data).lines()
+            .find_map(|x| x.strip_prefix("object ").map(|x| x.to_string()))
+            .ok_or_else(|| invalid(format!("tag {} has no object", hex(&id))))?;
+
+        self.peel(unhex(&target)?)
+    }
+
+    /// Map commit ids to the names of tags pointing to them
+    fn tags(&self) -> Result<HashMap<Oid, Vec<String>>> {
+        let mut tags: HashMap<Oid, Vec<String>> = HashMap::new();
+
+        for (name, id, peeled) in self.packed_refs()? {
+            if let Some(name) = name.strip_prefix("refs/tags/") {
+                let commit = match peeled {
+                    Some(peeled) => unhex(&peeled)?,
+                    None => self.peel(unhex(&id)?)?,
+                };
+
+                tags.entry(commit).or_default().push(name.to_string());
+            }
+        }
+
+        // loose tags take precedence over packed ones
+        let tag_dir = self.common_dir.join("refs").join("tags");
+        let mut dirs = vec![tag_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).into_iter().flatten().filter_map(|x| x.ok()) {
+                let path = entry.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let name = path.strip_prefix(&tag_dir).unwrap().to_string_lossy().replace('\\', "/");
+                let commit = self.peel(unhex(&fs::read_to_string(&path)?)?)?;
+                for names in tags.values_mut() {
+                    names.retain(|x| x != &name);
+                }
+                tags.entry(commit).or_default().push(name);
+            }
+        }
+
+        for names in tags.values_mut() {
+            names.sort();
+        }
+
+        Ok(tags)
+    }
+
+    /// Describe `HEAD` relative to the closest tag, like `git describe --tags --always`
+    pub(crate) fn describe(&self) -> Result<String> {
+        let head = unhex(&self.rev()?)?;
+        let tags = self.tags()?;
+
+        // walk history with the most recent commits first
+        let mut queue = BinaryHeap::from([(self.commit(&head)?.1, head)]);
+        let mut seen = HashSet::from([head]);
+        let mut found = None;
+
+        while let Some((_, id)) = queue.pop() {
+            if let Some(names) = tags.get(&id) {
+                found = Some((names.last().unwrap().clone(), id));
+                break;
+            }
+
+            for parent in self.commit(&id)?.0 {
+                if seen.insert(parent) {
+                    queue.push((self.commit(&parent)?.1, parent));
+                }
+            }
+        }
+
+        let short = hex(&head)[..7].to_string();
+        let (name, tagged) = match found {
+            Some(found) => found,
+            None => return Ok(short),
+        };
+
+        // count commits reachable from HEAD but not from the tag
+        let from_tag = self.ancestors(tagged)?;
+        let distance = self.ancestors(head)?.difference(&from_tag).count();
+
+        match distance {
+            0 => Ok(name),
+            n => Ok(format!("{}-{}-g{}", name, n, short)),
+        }
+    }
+
+    fn ancestors(&self, start: Oid) -> Result<HashSet<Oid>> {
+        let mut seen = HashSet::from([start]);
+        let mut stack = vec![start];
+
+        while let Some(id) = stack.pop() {
+            for parent in self.commit(&id)?.0 {
+                if seen.insert(parent) {
+                    stack.push(parent);
+                }
+            }
+        }
+
+        Ok(seen)
+    }
+
+    fn index(&self) -> Result<Vec<IndexEntry>> {
+        let data = match fs::read(self.git_dir.join("index")) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let u32_at = |pos: usize| -> Result<u32> {
+            data.get(pos..pos + 4)
+                .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+                .ok_or_else(|| invalid("truncated index"))
+        };
+        let u16_at = |pos: usize| -> Result<u16> {
+            data.get(pos..pos + 2)
+                .map(|x| u16::from_be_bytes(x.try_into().unwrap()))
+                .ok_or_else(|| invalid("truncated index"))
+        };
+        let byte_at = |pos: usize| data.get(pos).copied().ok_or_else(|| invalid("truncated index"));
+        // position of the nul byte terminating a path which starts at `pos`
+        let nul_at = |pos: usize| -> Result<usize> {
+            data.get(pos..)
+                .and_then(|x| x.iter().position(|x| *x == 0))
+                .map(|x| pos + x)
+                .ok_or_else(|| invalid("truncated index"))
+        };
+
+        if data.len() < 12 || &data[..4] != b"DIRC" {
+            return Err(invalid("invalid index"));
+        }
+        let version = u32_at(4)?;
+        if !(2..=4).contains(&version) {
+            return Err(invalid(format!("unsupported index version {}", version)));
+        }
+
+        let mut entries = Vec::new();
+        let mut pos = 12;
+        let mut prev_path: Vec<u8> = Vec::new();
+
+        for _ in 0..u32_at(8)? {
+            let start = pos;
+            let mtime = (u32_at(pos + 8)?, u32_at(pos + 12)?);
+            let mode = u32_at(pos + 24)?;
+            let size = u32_at(pos + 36)?;
+            let mut id = [0u8; 20];
+            id.copy_from_slice(data.get(pos + 40..pos + 60).ok_or_else(|| invalid("truncated index"))?);
+            let flags = u16_at(pos + 60)?;
+            pos += 62;
+
+            let mut extended = 0u16;
+            if version >= 3 && flags & 0x4000 != 0 {
+                extended = u16_at(pos)?;
+                pos += 2;
+            }
+
+            let path = if version == 4 {
+                // paths are prefix compressed against the previous entry
+                let mut byte = byte_at(pos)?;
+                let mut strip = (byte & 0x7f) as usize;
+                while byte & 0x80 != 0 {
+                    pos += 1;
+                    byte = byte_at(pos)?;
+                    strip = strip.checked_add(1)
+                        .and_then(|x| x.checked_mul(1 << 7))
+                        .ok_or_else(|| invalid("invalid index"))?
+                        | (byte & 0x7f) as usize;
+                }
+                pos += 1;
+
+                let end = nul_at(pos)?;
+                let mut path = prev_path[..prev_path.len().saturating_sub(strip)].to_vec();
+                path.extend_from_slice(&data[pos..end]);
+                pos = end + 1;
+
+                path
+            } else {
+                let end = nul_at(pos)?;
+                let path = data[pos..end].to_vec();
+
+                // entries are padded with 1-8 nul bytes to a multiple of eight
+                pos = start + ((end - start) / 8 + 1) * 8;
+
+                path
+            };
+
+            entries.push(IndexEntry {
+                path: String::from_utf8_lossy(&path).to_string(),
+                id,
+                mode,
+                size,
+                mtime,
+                stage: (flags >> 12) & 0x3,
+                skip_worktree: extended & 0x4000 != 0,
+                intent_to_add: extended & 0x2000 != 0,
+            });
+            prev_path = path;
+        }
+
+        Ok(entries)
+    }
+
+    /// Collect all blobs of a tree with their paths
+    fn tree(&self, id: &Oid, prefix: &str, out: &mut HashMap<String, (Oid, u32)>) -> Result<()> {
+        let (kind, data) = self.read_object(id)?;
+        if kind != Kind::Tree {
+            return Err(invalid(format!("{} is not a tree", hex(id))));
+        }
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let space = pos + data[pos..].iter().position(|x| *x == b' ').ok_or_else(|| invalid("invalid tree"))?;
+            let nul = space + data[space..].iter().position(|x| *x == 0).ok_or_else(|| invalid("invalid tree"))?;
+            let mode = u32::from_str_radix(&String::from_utf8_lossy(&data[pos..space]), 8)
+                .map_err(|_| invalid("invalid tree mode"))?;
+            let name = String::from_utf8_lossy(&data[space + 1..nul]);
+            let mut entry = [0u8; 20];
+            entry.copy_from_slice(data.get(nul + 1..nul + 21).ok_or_else(|| invalid("invalid tree"))?);
+            pos = nul + 21;
+
+            let path = format!("{}{}", prefix, name);
+            if mode == 0o40000 {
+                self.tree(&entry, &format!("{}/", path), out)?;
+            } else {
+                out.insert(path, (entry, mode));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether tracked files differ from `HEAD`, either staged or in the work tree
+    ///
+    /// Untracked files are ignored. Files changed by content filters (for
+    /// example line ending conversion) are reported as modified.
+    pub(crate) fn is_dirty(&self) -> Result<bool> {
+        let index = self.index()?;
+
+        // staged changes compared to the tree of HEAD
+        let mut head_tree = HashMap::new();
+        if let Some(head) = self.head()?.1 {
+            if let (_, _, Some(tree)) = self.commit(&unhex(&head)?)? {
+                self.tree(&tree, "", &mut head_tree)?;
+            }
+        }
+
+        if index.len() != head_tree.len() {
+            return Ok(true);
+        }
+
+        for entry in &index {
+            if entry.stage != 0 || entry.intent_to_add {
+                return Ok(true);
+            }
+
+            match head_tree.get(&entry.path) {
+                Some((id, mode)) if *id == entry.id && *mode == entry.mode => {},
+                _ => return Ok(true),
+            }
+        }
+
+        // changes of the work tree compared to the index
+        for entry in &index {
+            // submodules and sparse entries are not checked out here
+            if entry.mode == 0o160000 || entry.skip_worktree {
+                continue;
+            }
+
+            let path = self.work_dir.join(&entry.path);
+            let meta = match fs::symlink_metadata(&path) {
+                Ok(meta) => meta,
+                Err(_) => return Ok(true),
+            };
+
+            let content = if entry.mode == 0o120000 {
+                fs::read_link(&path)?.to_string_lossy().as_bytes().to_vec()
+            } else {
+                if meta.len() as u32 != entry.size {
+                    return Ok(true);
+                }
+
+                let mtime = meta.modified()?.duration_since(std::time::UNIX_EPOCH)
+                    .map(|x| (x.as_secs() as u32, x.subsec_nanos()))
+                    .unwrap_or((0, 0));
+                if mtime == entry.mtime {
+                    continue;
+                }
+
+                fs::read(&path)?
+            };
+
+            let mut hasher = sha1_smol::Sha1::new();
+            hasher.update(format!("blob {}\0", content.len()).as_bytes());
+            hasher.update(&content);
+            if hasher.digest().bytes() != entry.id {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) -> Option<String> {
+        let output = Command::new("git").args(args).current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "tsap").env("GIT_AUTHOR_EMAIL", "tsap@example.com")
+            .env("GIT_COMMITTER_NAME", "tsap").env("GIT_COMMITTER_EMAIL", "tsap@example.com")
+            .output().ok()?;
+
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    #[test]
+    fn test_corrupt() {
+        assert!(unhex(&"é".repeat(20)).is_err());
+        assert!(apply_delta(b"base", &[4, 4, 0x91]).is_err());
+        assert!(apply_delta(b"base", &[0xff; 16]).is_err());
+
+        let dir = std::env::temp_dir().join("tsap_tests").join("git_corrupt");
+        fs::create_dir_all(&dir).unwrap();
+        let mut idx = vec![0xff, b't', b'O', b'c', 0, 0, 0, 2];
+        idx.extend(std::iter::repeat(0xff).take(256 * 4));
+        fs::write(dir.join("pack.idx"), &idx).unwrap();
+        assert!(PackIndex::open(&dir.join("pack.idx")).is_err());
+
+        // an object which is a delta on itself
+        let id = [7u8; 20];
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        pack.push(0x70);
+        pack.extend_from_slice(&id);
+        fs::write(dir.join("pack.pack"), &pack).unwrap();
+
+        let repo = Repository {
+            git_dir: dir.clone(),
+            common_dir: dir.clone(),
+            work_dir: dir.clone(),
+            packs: vec![PackIndex { pack: dir.join("pack.pack"), ids: vec![id], offsets: vec![12], file: RefCell::new(None) }],
+            commits: RefCell::new(HashMap::new()),
+        };
+        match repo.read_object(&id) {
+            Err(Error::Git(msg)) => assert!(msg.contains("delta chain"), "{}", msg),
+            x => panic!("expected git error, got {:?}", x.map(|x| x.0)),
+        }
+    }
+
+    #[test]
+    fn test_repository() {
+        let dir = std::env::temp_dir().join("tsap_tests").join("git");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf")).unwrap();
+
+        // the test repository is created with the git binary
+        git(&dir, &["init", "-q", "-b", "main"]).expect("git is required to create the test repository");
+
+        fs::write(dir.join("conf/main.toml"), "seed = 1").unwrap();
+        git(&dir, &["add", "."]).unwrap();
+        git(&dir, &["commit", "-q", "-m", "first"]).unwrap();
+        git(&dir, &["tag", "-a", "v1.0", "-m", "release"]).unwrap();
+        fs::write(dir.join("conf/main.toml"), "seed = 2").unwrap();
+        git(&dir, &["commit", "-q", "-am", "second"]).unwrap();
+
+        let check = |dir: &Path| {
+            let repo = Repository::discover(&dir.join("conf")).unwrap();
+            assert_eq!(repo.rev().unwrap(), git(dir, &["rev-parse", "HEAD"]).unwrap());
+            assert_eq!(repo.head().unwrap().0.as_deref(), Some("main"));
+            assert_eq!(repo.describe().unwrap(), git(dir, &["describe", "--tags", "--always"]).unwrap());
+            assert!(!repo.is_dirty().unwrap());
+        };
+
+        check(&dir);
+
+        // packed objects and references
+        git(&dir, &["gc", "-q", "--aggressive"]).unwrap();
+        check(&dir);
+
+        fs::write(dir.join("conf/main.toml"), "seed = 3").unwrap();
+        assert!(Repository::discover(&dir).unwrap().is_dirty().unwrap());
+
+        git(&dir, &["add", "."]).unwrap();
+        assert!(Repository::discover(&dir).unwrap().is_dirty().unwrap());
+    }
+}
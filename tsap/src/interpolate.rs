@@ -0,0 +1,388 @@
+//! Resolve `${path.to.key}` references between values of a configuration
+//!
+//! A string consisting of a single reference is replaced by the referenced
+//! value and keeps its type, references embedded in longer strings are
+//! formatted and concatenated. Write `\${` for a literal `${`.
+//!
+//! Templates depending on other values, like `{ expr = "4 * ${d_model}" }`,
+//! are evaluated here as well. Conditionals (`if` and `switch`) are replaced by
+//! their selected branch in a separate pass, because a branch may contain
+//! further templates.
+
+use std::collections::HashMap;
+use toml::{value::Table, Value};
+
+use crate::{Result, Error, Path, TemplateKeys, expr};
+
+/// Replace all references in a configuration tree with their values
+pub(crate) fn interpolate(root: &Value, keys: TemplateKeys) -> Result<Value> {
+    let mut resolver = Resolver {
+        root,
+        keys,
+        cache: HashMap::new(),
+        stack: Vec::new(),
+    };
+
+    resolver.resolve("", root)
+}
+
+/// Resolve references in a value outside of the configuration, like a pattern
+///
+/// `key` names the value in error messages.
+pub(crate) fn interpolate_value(root: &Value, keys: TemplateKeys, key: &str, val: &Value) -> Result<Value> {
+    let mut resolver = Resolver {
+        root,
+        keys,
+        cache: HashMap::new(),
+        stack: Vec::new(),
+    };
+
+    resolver.resolve(key, val)
+}
+
+/// Replace conditionals with their selected branch
+///
+/// Returns whether any conditional was found. Branches are not resolved, a
+/// conditional without selected branch removes the key.
+pub(crate) fn select(root: &Value, keys: TemplateKeys) -> Result<(Value, bool)> {
+    let mut resolver = Resolver {
+        root,
+        keys,
+        cache: HashMap::new(),
+        stack: Vec::new(),
+    };
+
+    let mut selected = false;
+    let root = resolver.select("", root, &mut selected)?
+        .unwrap_or_else(|| Value::Table(Table::new()));
+
+    Ok((root, selected))
+}
+
+/// Part of a string with references
+enum Segment {
+    Literal(String),
+    Reference(String),
+}
+
+/// Split a string into literal parts and references
+fn segments(key: &str, val: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut rest = val;
+
+    while let Some(idx) = rest.find("${") {
+        if rest[..idx].ends_with('\\') {
+            literal.push_str(&rest[..idx - 1]);
+            literal.push_str("${");
+            rest = &rest[idx + 2..];
+            continue;
+        }
+
+        literal.push_str(&rest[..idx]);
+        let end = rest[idx..].find('}')
+            .ok_or_else(|| Error::InvalidReference(key.into(), format!("unterminated reference in \"{}\"", val)))?;
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+        }
+        segments.push(Segment::Reference(rest[idx + 2..idx + end].trim().to_string()));
+        rest = &rest[idx + end + 1..];
+    }
+
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    Ok(segments)
+}
+
+struct Resolver<'a> {
+    root: &'a Value,
+    keys: TemplateKeys,
+    /// Resolved values of referenced keys
+    cache: HashMap<String, Value>,
+    /// Keys currently being resolved, used to detect cycles
+    stack: Vec<String>,
+}
+
+fn join(key: &str, k: &str) -> String {
+    match key.is_empty() {
+        true => k.to_string(),
+        false => format!("{}.{}", key, k),
+    }
+}
+
+impl<'a> Resolver<'a> {
+    fn is_conditional(&self, t: &Table) -> bool {
+        self.keys.find(t, "if").is_some() || self.keys.find(t, "switch").is_some()
+    }
+
+    fn select(&mut self, key: &str, val: &Value, selected: &mut bool) -> Result<Option<Value>> {
+        match val {
+            Value::Table(t) if self.is_conditional(t) => {
+                *selected = true;
+
+                match self.guarded(key, |x| x.branch(key, t))? {
+                    Some(branch) => self.select(key, &branch, selected),
+                    None => Ok(None),
+                }
+            },
+            Value::Table(t) => {
+                let mut res = Table::new();
+                for (k, v) in t {
+                    if let Some(v) = self.select(&join(key, k), v, selected)? {
+                        res.insert(k.clone(), v);
+                    }
+                }
+
+                Ok(Some(Value::Table(res)))
+            },
+            Value::Array(a) => {
+                let mut res = Vec::new();
+                for (i, v) in a.iter().enumerate() {
+                    res.extend(self.select(&join(key, &i.to_string()), v, selected)?);
+                }
+
+                Ok(Some(Value::Array(res)))
+            },
+            x => Ok(Some(x.clone())),
+        }
+    }
+
+    /// Evaluate the branch selected by an `if` or `switch` table
+    fn branch(&mut self, key: &str, t: &Table) -> Result<Option<Value>> {
+        if let Some(cond_key) = self.keys.find(t, "if") {
+            let cond = match &t[&cond_key] {
+                Value::Boolean(b) => Value::Boolean(*b),
+                Value::String(expr) => expr::evaluate(key, expr, &mut |target| self.reference(key, target))?,
+                x => x.clone(),
+            };
+
+            return match cond {
+                Value::Boolean(true) => Ok(t.get("then").cloned()),
+                Value::Boolean(false) => Ok(t.get("else").cloned()),
+                x => Err(Error::InvalidExpression(
+                    key.into(),
+                    format!("condition is a {} instead of a boolean", x.type_str())
+                )),
+            };
+        }
+
+        let switch_key = self.keys.find(t, "switch").unwrap();
+        let case = match &t[&switch_key] {
+            Value::String(s) if s.contains("${") => self.resolve_segments(key, s)?,
+            x => x.clone(),
+        };
+        let case = match case {
+            Value::String(s) => s,
+            x @ (Value::Integer(_) | Value::Boolean(_)) => x.to_string(),
+            x => return Err(Error::InvalidTemplate(key.into(), format!("cannot switch on a {}", x.type_str()))),
+        };
+
+        let cases = match t.get("cases") {
+            Some(Value::Table(cases)) => cases,
+            _ => return Err(Error::InvalidTemplate(key.into(), "switch has no table of `cases`".into())),
+        };
+
+        match cases.get(&case).or_else(|| t.get("default")) {
+            Some(branch) => Ok(Some(branch.clone())),
+            None => {
+                let suggestion = crate::suggest::closest(&case, cases.keys().map(|x| x.as_str()))
+                    .map(|x| format!(", did you mean `{}`?", x))
+                    .unwrap_or_default();
+
+                Err(Error::InvalidTemplate(key.into(), format!(
+                    "no case `{}` in [{}]{}",
+                    case,
+                    cases.keys().cloned().collect::<Vec<_>>().join(", "),
+                    suggestion
+                )))
+            },
+        }
+    }
+
+    fn resolve(&mut self, key: &str, val: &Value) -> Result<Value> {
+        let join = |k: &str| join(key, k);
+
+        match val {
+            Value::String(s) if s.contains("${") => self.guarded(key, |x| x.resolve_segments(key, s)),
+            Value::Table(t) if self.keys.find(t, "expr").is_some() => {
+                let expr = match &t[&self.keys.find(t, "expr").unwrap()] {
+                    Value::String(expr) => expr,
+                    _ => return Err(Error::InvalidExpression(key.into(), "expression is not a string".into())),
+                };
+
+                self.guarded(key, |x| expr::evaluate(key, expr, &mut |target| x.reference(key, target)))
+            },
+            Value::Table(t) if self.is_conditional(t) => {
+                match self.guarded(key, |x| x.branch(key, t))? {
+                    Some(branch) => self.resolve(key, &branch),
+                    None => Err(Error::InvalidReference(key.into(), "conditional has no selected branch".into())),
+                }
+            },
+            Value::Table(t) => {
+                let mut resolved = Table::new();
+                for (k, v) in t {
+                    resolved.insert(k.clone(), self.resolve(&join(k), v)?);
+                }
+
+                Ok(Value::Table(resolved))
+            },
+            Value::Array(a) => a.iter().enumerate()
+                .map(|(i, v)| self.resolve(&join(&i.to_string()), v))
+                .collect::<Result<Vec<_>>>()
+                .map(Value::Array),
+            x => Ok(x.clone()),
+        }
+    }
+
+    /// Evaluate `f` for a value at `key`, failing if `key` depends on itself
+    fn guarded<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, key: &str, f: F) -> Result<T> {
+        if let Some(pos) = self.stack.iter().position(|x| x == key) {
+            let mut chain = self.stack[pos..].to_vec();
+            chain.push(key.to_string());
+
+            return Err(Error::ReferenceCycle(chain.join(" -> ")));
+        }
+
+        self.stack.push(key.to_string());
+        let res = f(self);
+        self.stack.pop();
+
+        res
+    }
+
+    fn resolve_segments(&mut self, key: &str, val: &str) -> Result<Value> {
+        let segments = segments(key, val)?;
+
+        // a single reference keeps the type of the referenced value
+        if let [Segment::Reference(target)] = segments.as_slice() {
+            return self.reference(key, target);
+        }
+
+        let mut out = String::new();
+        for segment in segments {
+            match segment {
+                Segment::Literal(lit) => out.push_str(&lit),
+                Segment::Reference(target) => match self.reference(key, &target)? {
+                    Value::String(s) => out.push_str(&s),
+                    Value::Integer(i) => out.push_str(&i.to_string()),
+                    Value::Float(f) => out.push_str(&f.to_string()),
+                    Value::Boolean(b) => out.push_str(&b.to_string()),
+                    Value::Datetime(d) => out.push_str(&d.to_string()),
+                    x => return Err(Error::InvalidReference(
+                        key.into(),
+                        format!("cannot embed {} `{}` in a string", x.type_str(), target)
+                    )),
+                },
+            }
+        }
+
+        Ok(Value::String(out))
+    }
+
+    fn reference(&mut self, key: &str, target: &str) -> Result<Value> {
+        if let Some(val) = self.cache.get(target) {
+            return Ok(val.clone());
+        }
+
+        let node = Path::from(target).absolute(self.root).get(self.root)
+            .ok_or_else(|| Error::UnknownReference(target.into(), key.into()))?;
+
+        let val = self.resolve(target, node)?;
+        self.cache.insert(target.to_string(), val.clone());
+
+        Ok(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        let root: Value = r#"
+            seed = 42
+            out_dir = "runs/${model.variant}-${seed}"
+            literal = "\\${seed}"
+            [model]
+            variant = "gru"
+            [experiment]
+            batch_size = 64
+            eval_batch = "${experiment.batch_size}"
+        "#.parse().unwrap();
+
+        let root = interpolate(&root, TemplateKeys::Bare).unwrap();
+        assert_eq!(root["out_dir"].as_str(), Some("runs/gru-42"));
+        assert_eq!(root["literal"].as_str(), Some("${seed}"));
+        assert_eq!(root["experiment"]["eval_batch"].as_integer(), Some(64));
+    }
+
+    #[test]
+    fn test_expr() {
+        let root: Value = r#"
+            total_steps = "${steps_per_epoch}"
+            steps_per_epoch = 1000
+            warmup_steps = { expr = "int(0.1 * ${total_steps})" }
+            [model]
+            d_model = 256
+            hidden = { "@expr" = "4 * ${model.d_model}" }
+            double = { expr = "2 * ${model.hidden}" }
+        "#.parse().unwrap();
+
+        let root = interpolate(&root, TemplateKeys::Bare).unwrap();
+        assert_eq!(root["warmup_steps"].as_integer(), Some(100));
+        assert_eq!(root["model"]["hidden"].as_integer(), Some(1024));
+        assert_eq!(root["model"]["double"].as_integer(), Some(2048));
+
+        let root: Value = "a = { expr = '${b} + 1' }\nb = { expr = '${a} + 1' }".parse().unwrap();
+        assert!(matches!(interpolate(&root, TemplateKeys::Bare), Err(Error::ReferenceCycle(..))));
+    }
+
+    #[test]
+    fn test_select() {
+        let root: Value = r#"
+            debug = true
+            workers = { if = "${debug}", then = 1, else = 16 }
+            batch = { expr = "8 * ${workers}" }
+            [experiment]
+            variant = "Test"
+            [model]
+            switch = "${experiment.variant}"
+            cases = { TrainModel = { dropout = 0.1 }, Test = { dropout = 0.0 } }
+            [optional]
+            if = "!${debug}"
+            then = { profile = true }
+        "#.parse().unwrap();
+
+        let (root, selected) = select(&root, TemplateKeys::Bare).unwrap();
+        assert!(selected);
+        assert!(root.get("optional").is_none());
+        assert_eq!(root["model"]["dropout"].as_float(), Some(0.0));
+
+        let root = interpolate(&root, TemplateKeys::Bare).unwrap();
+        assert_eq!(root["batch"].as_integer(), Some(8));
+
+        let root: Value = "v = 'Tset'\nm = { switch = '${v}', cases = { Test = 1 } }".parse().unwrap();
+        match select(&root, TemplateKeys::Bare) {
+            Err(Error::InvalidTemplate(_, msg)) => assert!(msg.contains("did you mean `Test`?")),
+            x => panic!("expected missing case, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_reference_cycle() {
+        let root: Value = "a = '${b}'\nb = 'x-${a}'".parse().unwrap();
+
+        match interpolate(&root, TemplateKeys::Bare) {
+            Err(Error::ReferenceCycle(chain)) => assert_eq!(chain, "a -> b -> a"),
+            x => panic!("expected reference cycle, got {:?}", x),
+        }
+
+        let root: Value = "a = '${missing}'".parse().unwrap();
+        assert!(matches!(interpolate(&root, TemplateKeys::Bare), Err(Error::UnknownReference(..))));
+    }
+}
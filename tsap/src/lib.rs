@@ -6,12 +6,42 @@ pub use tsap_macro::param;
 pub mod toml_builder;
 #[cfg(feature = "toml")]
 pub mod templates;
+#[cfg(feature = "toml")]
+mod interpolate;
+#[cfg(feature = "toml")]
+mod expr;
+#[cfg(feature = "toml")]
+mod suggest;
+#[cfg(feature = "toml")]
+mod git;
+#[cfg(feature = "toml")]
+mod provenance;
+#[cfg(feature = "toml")]
+pub mod run_dir;
+#[cfg(feature = "toml")]
+mod lock;
+#[cfg(feature = "toml")]
+pub mod plan;
+#[cfg(feature = "toml")]
+pub mod strategy;
 
 mod error;
 
-pub use error::{Result, Error};
+pub use error::{Result, Error, Unapplied};
 #[cfg(feature = "toml")]
 pub use toml_builder::{TomlBuilder, toml, serde, Path};
+#[cfg(feature = "toml")]
+pub use templates::{Policy, TemplateKeys};
+#[cfg(feature = "toml")]
+pub use provenance::Origin;
+#[cfg(feature = "toml")]
+pub use run_dir::RunDir;
+#[cfg(feature = "toml")]
+pub use plan::{Plan, Step};
+#[cfg(feature = "toml")]
+pub use strategy::{Strategy, Coercion};
+#[cfg(feature = "toml")]
+pub use lock::Mismatch;
 
 pub trait ParamGuard {
     type Error;
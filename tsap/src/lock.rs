@@ -0,0 +1,108 @@
+//! Lock files recording the output of every template expansion
+//!
+//! A lock file lists each template invocation with its key, arguments and
+//! resolved value. Replaying it resolves the same configuration without
+//! depending on commands, files or the time at which it is replayed.
+
+use std::fs;
+use std::fmt;
+use std::path::Path;
+use toml::{value::Table, Value};
+
+use crate::{Result, Error};
+use crate::templates::TemplateOutput;
+
+/// Templates which give a different result on every run by design, they are
+/// not compared to their live result when replaying
+const VOLATILE: [&str; 2] = ["now", "run_id"];
+
+/// Write all template expansions of a run to `path`
+pub(crate) fn write(path: &Path, outputs: &[TemplateOutput]) -> Result<()> {
+    let outputs = outputs.iter()
+        .map(|x| {
+            let mut table = Table::new();
+            table.insert("key".into(), Value::String(x.key.clone()));
+            table.insert("template".into(), Value::String(x.name.clone()));
+            table.insert("args".into(), Value::Table(x.args.clone()));
+            table.insert("value".into(), x.value.clone());
+
+            Value::Table(table)
+        })
+        .collect();
+
+    let mut root = Table::new();
+    root.insert("templates".into(), Value::Array(outputs));
+    fs::write(path, toml::to_string(&root)?)?;
+
+    Ok(())
+}
+
+/// A template whose live result differs from the recorded one, see
+/// [`TomlBuilder::verify_replay`](crate::TomlBuilder::verify_replay)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub key: String,
+    pub template: String,
+    pub recorded: Value,
+    /// live result or the error of the template
+    pub live: std::result::Result<Value, String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.live {
+            Ok(live) => write!(f, "template `{}` at `{}` gives {} instead of the recorded {}",
+                self.template, self.key, live, self.recorded),
+            Err(err) => write!(f, "could not verify template `{}` at `{}`: {}", self.template, self.key, err),
+        }
+    }
+}
+
+/// Recorded template outputs used instead of expanding templates
+#[derive(Clone)]
+pub(crate) struct Lock {
+    entries: Vec<Option<TemplateOutput>>,
+}
+
+impl Lock {
+    pub(crate) fn read(path: &Path) -> Result<Lock> {
+        let invalid = |msg: &str| Error::InvalidLock(format!("{}: {}", path.display(), msg));
+
+        let mut root: Table = toml::from_str(&fs::read_to_string(path)?)?;
+        let entries = match root.remove("templates") {
+            Some(Value::Array(entries)) => entries,
+            None => Vec::new(),
+            Some(_) => return Err(invalid("`templates` is not an array")),
+        };
+
+        let entries = entries.into_iter()
+            .map(|entry| {
+                let mut entry = match entry {
+                    Value::Table(entry) => entry,
+                    _ => return Err(invalid("entry is not a table")),
+                };
+
+                match (entry.remove("key"), entry.remove("template"), entry.remove("args"), entry.remove("value")) {
+                    (Some(Value::String(key)), Some(Value::String(name)), Some(Value::Table(args)), Some(value)) =>
+                        Ok(Some(TemplateOutput { key, name, args, value })),
+                    _ => Err(invalid("entry needs `key`, `template`, `args` and `value`")),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Lock { entries })
+    }
+
+    /// Take the first unused output recorded for this invocation
+    pub(crate) fn take(&mut self, key: &str, name: &str, args: &Table) -> Option<Value> {
+        self.entries.iter_mut()
+            .find(|x| matches!(x, Some(x) if x.key == key && x.name == name && &x.args == args))
+            .and_then(|x| x.take())
+            .map(|x| x.value)
+    }
+
+    /// Whether the live result of template `name` should be compared to the recorded one
+    pub(crate) fn verify(name: &str) -> bool {
+        !VOLATILE.contains(&name)
+    }
+}
@@ -0,0 +1,90 @@
+//! Dry run of `TomlBuilder::apply` listing what would happen
+//!
+//! The plan lists overrides in the order they are applied, template
+//! expansions with their arguments and overrides which wait for a template to
+//! create their target. Commands are not run while planning.
+
+use std::fmt;
+use toml::Value;
+
+use crate::Origin;
+
+/// A single step of a plan
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// An override is applied, possibly only in parts
+    Apply { action: String, origin: Origin },
+    /// An override waits until templates create its target
+    Defer { action: String, origin: Origin },
+    /// A template at `key` is expanded with `args`, the table containing it
+    Expand { key: String, template: String, args: Value },
+    /// Conditionals are replaced by their selected branch
+    Select,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Apply { action, origin } =>
+                write!(f, "apply  {} ({})", action, origin),
+            Step::Defer { action, origin } =>
+                write!(f, "defer  {} until templates are expanded ({})", action, origin),
+            Step::Expand { key, template, args } =>
+                write!(f, "expand `{}` at `{}` with {}", template, if key.is_empty() { "." } else { key }, inline(args)),
+            Step::Select =>
+                write!(f, "select branches of conditionals"),
+        }
+    }
+}
+
+/// Steps which `TomlBuilder::apply` would take, see [`TomlBuilder::plan`](crate::TomlBuilder::plan)
+#[derive(Debug, Default)]
+pub struct Plan {
+    steps: Vec<Step>,
+    /// deferred actions, which are only listed once
+    deferred: Vec<String>,
+}
+
+impl Plan {
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub(crate) fn push(&mut self, step: Step) {
+        if let Step::Defer { action, .. } = &step {
+            if self.deferred.contains(action) {
+                return;
+            }
+
+            self.deferred.push(action.clone());
+        }
+
+        self.steps.push(step);
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", i + 1, step)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Format a value on a single line, tables as inline tables
+pub(crate) fn inline(val: &Value) -> String {
+    match val {
+        Value::Table(t) if t.is_empty() => "{}".into(),
+        Value::Table(t) => {
+            let entries = t.iter()
+                .map(|(k, v)| format!("{} = {}", k, inline(v)))
+                .collect::<Vec<_>>();
+
+            format!("{{ {} }}", entries.join(", "))
+        },
+        Value::Array(a) => format!("[{}]", a.iter().map(inline).collect::<Vec<_>>().join(", ")),
+        x => x.to_string(),
+    }
+}
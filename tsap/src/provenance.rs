@@ -0,0 +1,568 @@
+//! Track where every value of a configuration comes from
+//!
+//! Each leaf (a scalar, or an empty table or array) keeps the chain of origins
+//! which wrote it, oldest first. Tables and arrays are tracked through their
+//! elements, so the key of an array element is its index, e.g. `layers.0.units`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use toml::Value;
+
+use crate::templates::LATE_TEMPLATES;
+
+/// Origin of a value in the resolved configuration
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// Read from a configuration file, either the main file, an included file
+    /// or one passed to `amend_file`
+    File { path: PathBuf, line: Option<usize> },
+    /// Command line argument at position `index`
+    Arg { index: usize, arg: String },
+    /// Set programmatically, with `amend` or when building from a string or value
+    Amend,
+    /// Output of a template
+    Template { name: String },
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::File { path, line: Some(line) } => write!(f, "{}:{}", path.display(), line),
+            Origin::File { path, line: None } => write!(f, "{}", path.display()),
+            Origin::Arg { index, arg } => write!(f, "argument {} `{}`", index, arg),
+            Origin::Amend => write!(f, "amend"),
+            Origin::Template { name } => write!(f, "template `{}`", name),
+        }
+    }
+}
+
+/// Where values of a batch, e.g. an action or an included file, come from
+pub(crate) struct Source {
+    origin: Origin,
+    /// key at which the content is placed
+    base: String,
+    lines: Option<Lines>,
+}
+
+impl Source {
+    pub(crate) fn new(origin: Origin, base: String) -> Source {
+        Source { origin, base, lines: None }
+    }
+
+    /// Source of a file with `content`, leaves get the line they are defined at
+    pub(crate) fn file(path: PathBuf, base: String, content: &str) -> Source {
+        Source {
+            origin: Origin::File { path, line: None },
+            base,
+            lines: Some(Lines::scan(content)),
+        }
+    }
+
+    /// Origin of the leaf at `key`
+    pub(crate) fn origin(&self, key: &str) -> Origin {
+        match (&self.origin, &self.lines) {
+            (Origin::File { path, .. }, Some(lines)) => {
+                let relative = match self.base.is_empty() {
+                    true => key,
+                    false => key.strip_prefix(&self.base)
+                        .map(|x| x.trim_start_matches('.'))
+                        .unwrap_or(key),
+                };
+
+                Origin::File { path: path.clone(), line: lines.line(relative) }
+            },
+            (origin, _) => origin.clone(),
+        }
+    }
+}
+
+/// Line numbers of keys defined in a TOML document
+pub(crate) struct Lines(HashMap<String, usize>);
+
+impl Lines {
+    /// Find the line of every key and table header
+    ///
+    /// This is a lightweight scan, not a parser. Keys in inline tables and
+    /// multi-line arrays are attributed to the line of their parent key.
+    pub(crate) fn scan(content: &str) -> Lines {
+        let mut lines = HashMap::new();
+        let mut prefix: Vec<String> = Vec::new();
+        let mut arrays: HashMap<String, usize> = HashMap::new();
+        let mut state = ScanState::default();
+
+        for (num, line) in content.lines().enumerate() {
+            let trimmed = line.trim();
+
+            if state.is_top_level() {
+                if let Some(header) = trimmed.strip_prefix("[[") {
+                    let path = header_path(header.split("]]").next().unwrap_or(""), &arrays);
+                    let idx = arrays.entry(path.join(".")).or_insert(0);
+
+                    prefix = path;
+                    prefix.push(idx.to_string());
+                    *idx += 1;
+                    lines.insert(prefix.join("."), num + 1);
+
+                    continue;
+                } else if let Some(header) = trimmed.strip_prefix('[') {
+                    prefix = header_path(header.split(']').next().unwrap_or(""), &arrays);
+                    lines.insert(prefix.join("."), num + 1);
+
+                    continue;
+                } else if let Some(eq) = find_unquoted(trimmed, '=') {
+                    let mut key = prefix.clone();
+                    key.extend(split_key(&trimmed[..eq]));
+                    lines.insert(key.join("."), num + 1);
+
+                    state.scan(&trimmed[eq + 1..]);
+                    continue;
+                }
+            }
+
+            state.scan(line);
+        }
+
+        Lines(lines)
+    }
+
+    /// Line of `key`, or of the closest parent defined in the document
+    pub(crate) fn line(&self, key: &str) -> Option<usize> {
+        let mut key = key;
+
+        loop {
+            if let Some(line) = self.0.get(key) {
+                return Some(*line);
+            }
+
+            key = &key[..key.rfind('.')?];
+        }
+    }
+}
+
+/// Nesting of brackets and multi-line strings while scanning values
+#[derive(Default)]
+struct ScanState {
+    depth: usize,
+    multiline: Option<&'static str>,
+}
+
+impl ScanState {
+    fn is_top_level(&self) -> bool {
+        self.depth == 0 && self.multiline.is_none()
+    }
+
+    fn scan(&mut self, line: &str) {
+        let mut rest = line;
+
+        while let Some(c) = rest.chars().next() {
+            if let Some(delim) = self.multiline {
+                match rest.find(delim) {
+                    Some(idx) => { rest = &rest[idx + 3..]; self.multiline = None; },
+                    None => return,
+                }
+                continue;
+            }
+
+            if rest.starts_with("\"\"\"") || rest.starts_with("'''") {
+                self.multiline = Some(if c == '"' { "\"\"\"" } else { "'''" });
+                rest = &rest[3..];
+                continue;
+            }
+
+            match c {
+                '#' => return,
+                '"' | '\'' => {
+                    // skip a single line string
+                    rest = match find_closing(&rest[1..], c) {
+                        Some(idx) => &rest[idx + 2..],
+                        None => return,
+                    };
+                    continue;
+                },
+                '[' | '{' => self.depth += 1,
+                ']' | '}' => self.depth = self.depth.saturating_sub(1),
+                _ => {},
+            }
+
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+}
+
+/// Position of the quote closing a string, escapes only apply to basic strings
+fn find_closing(s: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+
+    for (idx, c) in s.char_indices() {
+        match c {
+            '\\' if quote == '"' && !escaped => escaped = true,
+            c if c == quote && !escaped => return Some(idx),
+            _ => escaped = false,
+        }
+    }
+
+    None
+}
+
+/// Position of `needle` outside of quoted strings
+fn find_unquoted(s: &str, needle: char) -> Option<usize> {
+    let mut quote = None;
+
+    for (idx, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, c) if c == needle => return Some(idx),
+            _ => {},
+        }
+    }
+
+    None
+}
+
+/// Split a dotted key into segments and remove quotes
+fn split_key(key: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut rest = key;
+
+    while let Some(idx) = find_unquoted(rest, '.') {
+        segments.push(unquote(&rest[..idx]));
+        rest = &rest[idx + 1..];
+    }
+    segments.push(unquote(rest));
+
+    segments
+}
+
+fn unquote(segment: &str) -> String {
+    segment.trim().trim_matches(|x| x == '"' || x == '\'').to_string()
+}
+
+/// Key of a table header, parents which are arrays of tables refer to their last element
+fn header_path(header: &str, arrays: &HashMap<String, usize>) -> Vec<String> {
+    let segments = split_key(header);
+    let num = segments.len();
+    let mut path = Vec::new();
+
+    for (i, segment) in segments.into_iter().enumerate() {
+        path.push(segment);
+
+        if i + 1 < num {
+            if let Some(count) = arrays.get(&path.join(".")) {
+                path.push(count.saturating_sub(1).to_string());
+            }
+        }
+    }
+
+    path
+}
+
+fn join(key: &str, k: &str) -> String {
+    match key.is_empty() {
+        true => k.to_string(),
+        false => format!("{}.{}", key, k),
+    }
+}
+
+/// Escaped keys appear without the `@@` prefix in the result
+fn normalize(key: &str) -> String {
+    key.split('.')
+        .map(|x| x.strip_prefix("@@").unwrap_or(x))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Collect the keys of all leaves below `key`
+pub(crate) fn leaves(key: &str, value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::Table(map) if !map.is_empty() =>
+            map.iter().for_each(|(k, v)| leaves(&join(key, k), v, out)),
+        Value::Array(elms) if !elms.is_empty() =>
+            elms.iter().enumerate().for_each(|(i, v)| leaves(&join(key, &i.to_string()), v, out)),
+        _ => out.push(normalize(key)),
+    }
+}
+
+/// Whether `key` is equal to or below `prefix`
+fn is_below(key: &str, prefix: &str) -> bool {
+    prefix.is_empty() || key == prefix || (key.starts_with(prefix) && key[prefix.len()..].starts_with('.'))
+}
+
+/// Leaves written and array elements moved while merging an action, in order
+#[derive(Default)]
+pub(crate) struct Changes {
+    changes: Vec<Change>,
+    /// keys of references which replaced a value of another type, with that value
+    pub(crate) references: Vec<(Vec<String>, Value)>,
+}
+
+enum Change {
+    Write(String),
+    Shift(String, usize, isize),
+}
+
+impl Changes {
+    /// All leaves of `value` at `key` are written
+    pub(crate) fn write(&mut self, key: &[String], value: &Value) {
+        let mut keys = Vec::new();
+        leaves(&key.join("."), value, &mut keys);
+
+        self.changes.extend(keys.into_iter().map(Change::Write));
+    }
+
+    /// Elements of the array `elms` at `key` are written, starting at index `from`
+    pub(crate) fn elements(&mut self, key: &[String], elms: &[Value], from: usize) {
+        for (idx, elm) in elms.iter().enumerate().skip(from) {
+            let mut child = key.to_vec();
+            child.push(idx.to_string());
+
+            self.write(&child, elm);
+        }
+    }
+
+    /// Elements of the array at `key` move, see [`Provenance::shift`]
+    pub(crate) fn shift(&mut self, key: &[String], from: usize, delta: isize) {
+        self.changes.push(Change::Shift(key.join("."), from, delta));
+    }
+
+    /// A reference at `key` replaced `target`, its type is checked after interpolation
+    pub(crate) fn reference(&mut self, key: &[String], target: Value) {
+        self.references.push((key.to_vec(), target));
+    }
+}
+
+/// Chains of origins for every leaf of a configuration
+#[derive(Clone, Default)]
+pub(crate) struct Provenance {
+    chains: HashMap<String, Vec<Origin>>,
+    /// origins of files loaded by a template which is currently expanded
+    loaded: HashMap<String, Vec<Origin>>,
+}
+
+impl Provenance {
+    /// Record that all leaves of `value` at `key` were written by `source`
+    pub(crate) fn record(&mut self, key: &str, value: &Value, source: &Source) {
+        let mut keys = Vec::new();
+        leaves(key, value, &mut keys);
+
+        for leaf in keys {
+            let origin = source.origin(&leaf);
+            self.push(leaf, origin);
+        }
+    }
+
+    /// Append `origin` to the chain of the leaf at `key`
+    pub(crate) fn push(&mut self, key: String, origin: Origin) {
+        let chain = self.chains.entry(normalize(&key)).or_default();
+
+        if chain.last() != Some(&origin) {
+            chain.push(origin);
+        }
+    }
+
+    /// Apply the changes of a merge, written leaves get their origin from `source`
+    pub(crate) fn apply(&mut self, changes: Changes, source: &Source) {
+        for change in changes.changes {
+            match change {
+                Change::Write(leaf) => {
+                    let origin = source.origin(&leaf);
+                    self.push(leaf, origin);
+                },
+                Change::Shift(key, from, delta) => self.shift(&key, from, delta),
+            }
+        }
+    }
+
+    /// Remember the origins of a file loaded by a template at `key`
+    pub(crate) fn loaded(&mut self, key: &str, value: &Value, source: &Source) {
+        let mut keys = Vec::new();
+        leaves(key, value, &mut keys);
+
+        for leaf in keys {
+            let origin = source.origin(&leaf);
+            self.loaded.entry(leaf).or_default().push(origin);
+        }
+    }
+
+    /// Record the output of template `name` at `key`, `field` is the key of
+    /// the template in the table
+    ///
+    /// New leaves start with the origins of the template arguments. Leaves
+    /// which existed before, like keys next to the template, are kept.
+    pub(crate) fn template(&mut self, key: &str, field: &str, name: &str, value: &Value) {
+        let mut base = under(&self.chains, &join(key, field));
+        base.push(Origin::Template { name: name.into() });
+
+        let mut keys = Vec::new();
+        leaves(key, value, &mut keys);
+
+        for leaf in keys {
+            if self.chains.contains_key(&leaf) {
+                continue;
+            }
+
+            let mut chain = base.clone();
+            chain.extend(self.loaded.remove(&leaf).unwrap_or_default());
+            self.chains.insert(leaf, chain);
+        }
+
+        self.loaded.clear();
+    }
+
+    /// Move chains of array elements at index `from` and after by `delta`
+    ///
+    /// Used after inserting into or removing from the array at `key`, the
+    /// chains of a removed element are dropped.
+    pub(crate) fn shift(&mut self, key: &str, from: usize, delta: isize) {
+        let prefix = join(&normalize(key), "");
+        let old = std::mem::take(&mut self.chains);
+
+        for (leaf, chain) in old {
+            let rest = match leaf.strip_prefix(&prefix) {
+                Some(rest) => rest,
+                None => { self.chains.insert(leaf, chain); continue },
+            };
+            let (idx, tail) = rest.split_at(rest.find('.').unwrap_or(rest.len()));
+
+            match idx.parse::<usize>() {
+                Ok(idx) if idx >= from => {
+                    if delta < 0 && idx == from {
+                        continue;
+                    }
+
+                    let idx = (idx as isize + delta) as usize;
+                    self.chains.insert(format!("{}{}{}", prefix, idx, tail), chain);
+                },
+                _ => { self.chains.insert(leaf, chain); },
+            }
+        }
+    }
+
+    /// Drop chains of keys which no longer exist in `root`
+    ///
+    /// Leaves produced by late templates (`expr`, `if` and `switch`) have no
+    /// chain yet, they inherit the origins of the template arguments.
+    pub(crate) fn retain(&mut self, root: &Value) {
+        let mut keys = Vec::new();
+        leaves("", root, &mut keys);
+
+        let old = std::mem::take(&mut self.chains);
+        for leaf in keys {
+            let chain = match old.get(&leaf) {
+                Some(chain) => Some(chain.clone()),
+                None => late_template(&old, &leaf),
+            };
+
+            if let Some(chain) = chain {
+                self.chains.insert(leaf, chain);
+            }
+        }
+    }
+
+    /// Chains of all leaves at or below `key`, sorted by key
+    pub(crate) fn explain(&self, key: &str) -> Vec<(String, Vec<Origin>)> {
+        let key = normalize(key);
+        let mut chains = self.chains.iter()
+            .filter(|(k, _)| is_below(k, &key))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        chains.sort_by(|a, b| a.0.cmp(&b.0));
+
+        chains
+    }
+}
+
+/// Union of all chains at or below `prefix`, in order of their keys
+fn under(chains: &HashMap<String, Vec<Origin>>, prefix: &str) -> Vec<Origin> {
+    let mut keys = chains.keys()
+        .filter(|x| is_below(x, prefix))
+        .collect::<Vec<_>>();
+    keys.sort();
+
+    let mut union: Vec<Origin> = Vec::new();
+    for origin in keys.into_iter().flat_map(|x| &chains[x]) {
+        if !union.contains(origin) {
+            union.push(origin.clone());
+        }
+    }
+
+    union
+}
+
+/// Chain of a leaf replaced by a late template at the leaf itself or a parent
+fn late_template(old: &HashMap<String, Vec<Origin>>, leaf: &str) -> Option<Vec<Origin>> {
+    let mut prefix = leaf;
+
+    loop {
+        for name in LATE_TEMPLATES.iter() {
+            let mut chain = under(old, &join(prefix, name));
+            chain.extend(under(old, &join(prefix, &format!("@{}", name))));
+            if chain.is_empty() {
+                continue;
+            }
+
+            // a leaf inside a selected branch, e.g. `x.then.a` became `x.a`
+            let rest = &leaf[prefix.len()..];
+            if !rest.is_empty() {
+                let mut keys = old.keys()
+                    .filter(|x| x.starts_with(prefix) && x.ends_with(rest) && x.len() > leaf.len())
+                    .collect::<Vec<_>>();
+                keys.sort();
+
+                for origin in keys.into_iter().flat_map(|x| &old[x]) {
+                    if !chain.contains(origin) {
+                        chain.push(origin.clone());
+                    }
+                }
+            }
+
+            chain.push(Origin::Template { name: name.to_string() });
+            return Some(chain);
+        }
+
+        prefix = &prefix[..prefix.rfind('.')?];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let lines = Lines::scan(r#"
+seed = 1
+name = "a = b # c"
+text = """
+x = 1
+"""
+
+[model]
+layers = [
+    1, 2,
+]
+"dotted.key" = 2
+inline = { a = 1 }
+
+[[runs]]
+id = 1
+
+[[runs]]
+id = 2
+
+[runs.opt]
+lr = 0.1
+"#);
+
+        assert_eq!(lines.line("seed"), Some(2));
+        assert_eq!(lines.line("name"), Some(3));
+        assert_eq!(lines.line("x"), None);
+        assert_eq!(lines.line("model.layers.1"), Some(9));
+        assert_eq!(lines.line("model.dotted.key"), Some(12));
+        assert_eq!(lines.line("model.inline.a"), Some(13));
+        assert_eq!(lines.line("runs.0.id"), Some(16));
+        assert_eq!(lines.line("runs.1.id"), Some(19));
+        assert_eq!(lines.line("runs.1.opt.lr"), Some(22));
+    }
+}
@@ -0,0 +1,130 @@
+//! Output directories for runs, with the configuration which produced them
+//!
+//! After `TomlBuilder::apply` a new directory is created from a pattern and
+//! receives the resolved configuration (`config.toml`), the command line
+//! arguments (`overrides.txt`) and every template expansion with its
+//! arguments (`templates.toml`). Loading `config.toml` with `from_file`
+//! reproduces the configuration of the run, `templates.toml` is a lock file
+//! which can be replayed.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use toml::{value::Table, Value};
+
+use crate::{Result, Error, TemplateKeys, lock};
+use crate::templates::TemplateOutput;
+use crate::interpolate::interpolate_value;
+
+/// Default pattern, a directory per day and one per run in it
+pub const DEFAULT_PATTERN: &str = "outputs/%Y-%m-%d/%H-%M-%S";
+
+/// Where to create the output directory of a run
+///
+/// The pattern is formatted with the start time of the run (see `chrono`'s
+/// `strftime` for the syntax) and may reference configuration values, for
+/// example `"outputs/${experiment.variant}/%Y-%m-%d_%H-%M-%S"`. If the
+/// directory already exists a suffix `_1`, `_2`, .. is appended.
+///
+/// ```ignore
+/// let main = Main::from_file("conf/main.toml")?
+///     .run_dir(RunDir::default().key("output_dir"))
+///     .amend_args()?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct RunDir {
+    pattern: String,
+    key: Option<String>,
+}
+
+impl Default for RunDir {
+    fn default() -> RunDir {
+        RunDir::new(DEFAULT_PATTERN)
+    }
+}
+
+impl RunDir {
+    pub fn new<T: Into<String>>(pattern: T) -> RunDir {
+        RunDir { pattern: pattern.into(), key: None }
+    }
+
+    /// Store the path of the directory at `key` of the configuration
+    pub fn key<T: Into<String>>(mut self, key: T) -> RunDir {
+        self.key = Some(key.into());
+
+        self
+    }
+
+    /// Create the directory and store the path in `root` if requested
+    pub(crate) fn create(&self, root: &mut Value, keys: TemplateKeys, started: DateTime<Local>) -> Result<PathBuf> {
+        let items = chrono::format::StrftimeItems::new(&self.pattern).collect::<Vec<_>>();
+        if items.contains(&chrono::format::Item::Error) {
+            return Err(Error::InvalidRunDir(format!("invalid time format in `{}`", self.pattern)));
+        }
+        let formatted = started.format_with_items(items.into_iter()).to_string();
+
+        let path = match interpolate_value(root, keys, "run_dir", &Value::String(formatted))? {
+            Value::String(path) => PathBuf::from(path),
+            x => PathBuf::from(x.to_string()),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // creating the directory fails if it exists, also when created by a concurrent run
+        let mut dir = path.clone();
+        let mut suffix = 0;
+        loop {
+            match fs::create_dir(&dir) {
+                Ok(()) => break,
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    suffix += 1;
+                    dir = PathBuf::from(format!("{}_{}", path.display(), suffix));
+                },
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if let Some(key) = &self.key {
+            insert(root, key, Value::String(dir.display().to_string()))
+                .ok_or_else(|| Error::InvalidRunDir(format!("can not store the directory at `{}`", key)))?;
+        }
+
+        Ok(dir)
+    }
+
+    /// Save the configuration, arguments and template outputs of the run
+    ///
+    /// `root` is expected to be escaped already, see [`templates::escape`](crate::templates::escape).
+    pub(crate) fn save(dir: &Path, root: &Value, args: &[String], outputs: &[TemplateOutput]) -> Result<()> {
+        fs::write(dir.join("config.toml"), toml::to_string(root)?)?;
+
+        let mut overrides = args.join("\n");
+        if !overrides.is_empty() {
+            overrides.push('\n');
+        }
+        fs::write(dir.join("overrides.txt"), overrides)?;
+
+        lock::write(&dir.join("templates.toml"), outputs)?;
+
+        Ok(())
+    }
+}
+
+/// Set `val` at a dotted `key`, creating missing tables on the way
+fn insert(root: &mut Value, key: &str, val: Value) -> Option<()> {
+    let mut segments = key.split('.').collect::<Vec<_>>();
+    let last = segments.pop()?;
+
+    let mut node = root;
+    for segment in segments {
+        node = node.as_table_mut()?
+            .entry(segment)
+            .or_insert_with(|| Value::Table(Table::new()));
+    }
+
+    node.as_table_mut()?.insert(last.into(), val);
+
+    Some(())
+}
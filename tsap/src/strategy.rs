@@ -0,0 +1,187 @@
+//! Strategies for merging values into an existing configuration
+//!
+//! By default tables are merged key by key and every other value, arrays
+//! included, is replaced. A strategy changes this for a single merge (see
+//! [`TomlBuilder::amend_with`](crate::TomlBuilder::amend_with)) or for all
+//! merges at or below a path (see [`TomlBuilder::merge_strategy`](crate::TomlBuilder::merge_strategy)).
+//! Scalars of a different type are converted according to a [`Coercion`].
+
+use std::fmt;
+use std::str::FromStr;
+use toml::{value::{Datetime, Table}, Value};
+
+use crate::{Error, Path, TemplateKeys};
+
+/// How a value is merged into an existing one
+#[derive(Debug, Clone, PartialEq)]
+pub enum Strategy {
+    /// Replace the existing value, tables included
+    Replace,
+    /// Merge tables key by key and arrays element by element
+    Deep,
+    /// Add the elements of an array after the existing ones
+    Append,
+    /// Add the elements of an array before the existing ones
+    Prepend,
+    /// Append elements of an array which do not exist yet
+    Union,
+    /// Merge tables in arrays with the same value at a key, append the others
+    MergeBy(String),
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    /// Parse `replace`, `deep`, `append`, `prepend`, `union` or `merge_by=key`
+    fn from_str(s: &str) -> Result<Strategy, Error> {
+        let strategy = match s.trim() {
+            "replace" => Strategy::Replace,
+            "deep" => Strategy::Deep,
+            "append" => Strategy::Append,
+            "prepend" => Strategy::Prepend,
+            "union" => Strategy::Union,
+            x => {
+                let key = x.strip_prefix("merge_by")
+                    .and_then(|x| x.trim_start().strip_prefix('='))
+                    .map(|x| x.trim().trim_matches(|c| c == '"' || c == '\''))
+                    .filter(|x| !x.is_empty())
+                    .ok_or_else(|| Error::InvalidStrategy(s.to_string()))?;
+
+                Strategy::MergeBy(key.to_string())
+            },
+        };
+
+        Ok(strategy)
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Strategy::Replace => write!(f, "replace"),
+            Strategy::Deep => write!(f, "deep"),
+            Strategy::Append => write!(f, "append"),
+            Strategy::Prepend => write!(f, "prepend"),
+            Strategy::Union => write!(f, "union"),
+            Strategy::MergeBy(key) => write!(f, "merge_by={}", key),
+        }
+    }
+}
+
+/// Conversions of values which override a value of another type
+///
+/// By default integers override floats and strings override numbers and
+/// datetimes if they parse as one. Other mismatches wait until templates are
+/// expanded, in strict mode they fail immediately.
+///
+/// ```ignore
+/// let builder = TomlBuilder::from_file("conf/main.toml")?
+///     .coercion(Coercion::default().string_to_number(false).strict());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coercion {
+    int_to_float: bool,
+    string_to_number: bool,
+    string_to_datetime: bool,
+    strict: bool,
+}
+
+impl Default for Coercion {
+    fn default() -> Coercion {
+        Coercion {
+            int_to_float: true,
+            string_to_number: true,
+            string_to_datetime: true,
+            strict: false,
+        }
+    }
+}
+
+impl Coercion {
+    /// Do not convert any values
+    pub fn none() -> Coercion {
+        Coercion {
+            int_to_float: false,
+            string_to_number: false,
+            string_to_datetime: false,
+            strict: false,
+        }
+    }
+
+    /// Convert integers overriding a float, `lr=1` sets `1.0`
+    pub fn int_to_float(mut self, enable: bool) -> Self {
+        self.int_to_float = enable;
+
+        self
+    }
+
+    /// Parse strings overriding an integer or float
+    pub fn string_to_number(mut self, enable: bool) -> Self {
+        self.string_to_number = enable;
+
+        self
+    }
+
+    /// Parse strings overriding a datetime
+    pub fn string_to_datetime(mut self, enable: bool) -> Self {
+        self.string_to_datetime = enable;
+
+        self
+    }
+
+    /// Fail when a value can not override a value of another type
+    ///
+    /// Tables on either side are exempt, they may be templates which are not
+    /// expanded yet.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+
+        self
+    }
+
+    pub(crate) fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Convert `val` to the type of `target`, or return it unchanged
+    pub(crate) fn apply(&self, target: &Value, val: Value) -> Result<Value, Value> {
+        match (target, val) {
+            (Value::Float(_), Value::Integer(x)) if self.int_to_float => Ok(Value::Float(x as f64)),
+            (Value::Float(_), Value::String(x)) if self.string_to_number =>
+                x.trim().parse().map(Value::Float).map_err(|_| Value::String(x)),
+            (Value::Integer(_), Value::String(x)) if self.string_to_number =>
+                x.trim().parse().map(Value::Integer).map_err(|_| Value::String(x)),
+            (Value::Datetime(_), Value::String(x)) if self.string_to_datetime =>
+                x.trim().parse::<Datetime>().map(Value::Datetime).map_err(|_| Value::String(x)),
+            (_, val) => Err(val),
+        }
+    }
+}
+
+/// Rules which apply during a single merge
+pub(crate) struct Rules<'a> {
+    /// strategies registered for paths, the longest matching path wins
+    pub(crate) paths: &'a [(Path, Strategy)],
+    /// strategy of the merge itself, used where no path matches
+    pub(crate) merge: Option<&'a Strategy>,
+    pub(crate) coercion: Coercion,
+    /// names of all templates and which keys expand them
+    pub(crate) templates: &'a [&'a str],
+    pub(crate) keys: TemplateKeys,
+}
+
+impl<'a> Rules<'a> {
+    /// Strategy for the value at `key`
+    pub(crate) fn at(&self, key: &[String]) -> Option<&'a Strategy> {
+        self.paths.iter()
+            .filter(|(path, _)| key.starts_with(path.segments()))
+            .max_by_key(|(path, _)| path.segments().len())
+            .map(|(_, strategy)| strategy)
+            .or(self.merge)
+    }
+
+    /// Whether table `t` contains a template which is not expanded yet
+    pub(crate) fn has_template(&self, t: &Table) -> bool {
+        self.templates.iter().any(|x| self.keys.find(t, x).is_some())
+    }
+}
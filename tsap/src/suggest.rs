@@ -0,0 +1,53 @@
+//! Suggest close matches for misspelled names
+
+/// Number of single character edits (including swapping two adjacent
+/// characters) to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b) = (a.chars().collect::<Vec<_>>(), b.chars().collect::<Vec<_>>());
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in dist[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            dist[i][j] = (dist[i - 1][j] + 1)
+                .min(dist[i][j - 1] + 1)
+                .min(dist[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                dist[i][j] = dist[i][j].min(dist[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    dist[a.len()][b.len()]
+}
+
+/// Return the candidate closest to `target`, if it is close enough to be a typo
+pub(crate) fn closest<'a, I: IntoIterator<Item = &'a str>>(target: &str, candidates: I) -> Option<&'a str> {
+    let max_distance = (target.chars().count() / 3).max(1);
+
+    candidates.into_iter()
+        .map(|x| (edit_distance(target, x), x))
+        .filter(|(dist, _)| *dist <= max_distance)
+        .min_by_key(|(dist, _)| *dist)
+        .map(|(_, x)| x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_closest() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(closest("gur", ["bcresnet", "gru", "lstm"]), Some("gru"));
+        assert_eq!(closest("transformer", ["bcresnet", "gru"]), None);
+    }
+}
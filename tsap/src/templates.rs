@@ -1,127 +1,1600 @@
 use std::io::Read;
 use std::fs::File;
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::env;
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, Local, Utc};
+#[cfg(feature = "cmd")]
+use std::process::{Child, Command, Stdio};
+#[cfg(feature = "cmd")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "cmd")]
+use std::thread;
 use serde::Deserialize;
 use toml::{value::Table, Value};
 
+use crate::{Result, Error};
+use crate::provenance::{Provenance, Source};
+use crate::lock::{Lock, Mismatch};
+
 pub type DynTemplate = Box<dyn Template>;
 
 pub trait Template {
-    fn resolve(&mut self, key: String, map: Table, field: toml::Value) -> Value;
+    fn resolve(&mut self, ctx: &mut Context, key: String, map: Table, field: toml::Value) -> Result<Value>;
+
+    /// Options read from the table, an unmarked key of another template with
+    /// the same name is passed as option instead of being expanded
+    fn options(&self) -> &[&str] {
+        &[]
+    }
+}
+
+/// State shared by all templates while resolving a configuration
+///
+/// Keeps track of the chain of files included at each key, so that a file
+/// including itself (directly or via other files) is reported instead of
+/// expanding forever. Time and run identifier are fixed for a single run, so
+/// that all templates see the same values. The origin of every value is
+/// recorded as well, see [`TomlBuilder::explain`](crate::TomlBuilder::explain).
+#[derive(Clone, Default)]
+pub struct Context {
+    includes: HashMap<String, Vec<PathBuf>>,
+    pub(crate) policy: Policy,
+    pub(crate) seed: Option<u64>,
+    pub(crate) provenance: Provenance,
+    /// every template expansion of the run, in order
+    pub(crate) outputs: Vec<TemplateOutput>,
+    /// recorded outputs used instead of expanding templates
+    pub(crate) replay: Option<Lock>,
+    /// evaluate templates while replaying and compare them to the recorded output
+    pub(crate) verify_replay: bool,
+    /// templates of the last run whose live result differs from the recorded one
+    pub(crate) mismatches: Vec<Mismatch>,
+    /// plan only, commands are not run and nothing is written
+    pub(crate) dry_run: bool,
+    run: Option<Run>,
+}
+
+/// A single template expansion with its arguments and result
+#[derive(Clone)]
+pub(crate) struct TemplateOutput {
+    pub(crate) key: String,
+    pub(crate) name: String,
+    /// table containing the template, with the template key and its options
+    pub(crate) args: Table,
+    pub(crate) value: Value,
+}
+
+/// Values fixed for a single run of `TomlBuilder::apply`
+#[derive(Clone)]
+struct Run {
+    started: DateTime<Local>,
+    id: u128,
+    counters: HashMap<PathBuf, u64>,
 }
 
-pub struct Templates(HashMap<String, Box<dyn Template>>);
+impl Context {
+    /// Start a new run, templates will see a new time and run identifier
+    pub fn start_run(&mut self) {
+        let seed = self.seed.unwrap_or_else(|| {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|x| x.as_nanos() as u64)
+                .unwrap_or(0);
+
+            nanos ^ ((std::process::id() as u64) << 32)
+        });
+
+        // splitmix64 to spread the seed over the identifier
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^ (z >> 31)
+        };
+
+        self.outputs.clear();
+        self.run = Some(Run {
+            started: Local::now(),
+            id: ((next() as u128) << 64) | next() as u128,
+            counters: HashMap::new(),
+        });
+    }
+
+    /// Start time of the current run
+    pub(crate) fn started(&mut self) -> DateTime<Local> {
+        self.run().started
+    }
+
+    fn run(&mut self) -> &mut Run {
+        if self.run.is_none() {
+            self.start_run();
+        }
+
+        self.run.as_mut().unwrap()
+    }
+
+    /// Fail if the policy does not allow running `cmd` at `key`
+    pub fn check_command(&self, key: &str, cmd: &str) -> Result<()> {
+        match &self.policy.commands {
+            CommandPolicy::Allow => Ok(()),
+            CommandPolicy::Deny => Err(Error::PolicyViolation(
+                key.into(),
+                format!("running `{}` is not allowed", cmd)
+            )),
+            CommandPolicy::Allowlist(allowed) if allowed.iter().any(|x| x == cmd) => Ok(()),
+            CommandPolicy::Allowlist(_) => Err(Error::PolicyViolation(
+                key.into(),
+                format!("command `{}` is not in the allowlist", cmd)
+            )),
+        }
+    }
+
+    /// Fail if the policy restricts commands and `option` of the `cmd` template is set at `key`
+    ///
+    /// An allowlist only covers the command itself, an allowed command must
+    /// not run in another shell, directory or environment.
+    pub fn check_command_option(&self, key: &str, option: &str) -> Result<()> {
+        match &self.policy.commands {
+            CommandPolicy::Allow => Ok(()),
+            _ => Err(Error::PolicyViolation(
+                key.into(),
+                format!("`{}` of commands can not be changed with a restricted policy", option)
+            )),
+        }
+    }
+
+    /// Fail if the policy does not allow reading `path` at `key`
+    pub fn check_path(&self, key: &str, path: &Path) -> Result<()> {
+        let roots = match &self.policy.roots {
+            Some(roots) => roots,
+            None => return Ok(()),
+        };
+
+        // files which are about to be created are checked by their directory
+        let canonical = match (path.exists(), path.parent(), path.file_name()) {
+            (false, Some(parent), Some(name)) => {
+                let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+                parent.canonicalize()?.join(name)
+            },
+            _ => path.canonicalize()?,
+        };
+        let allowed = roots.iter()
+            .filter_map(|x| x.canonicalize().ok())
+            .any(|x| canonical.starts_with(x));
+
+        match allowed {
+            true => Ok(()),
+            false => Err(Error::PolicyViolation(
+                key.into(),
+                format!("`{}` is outside of the allowed directories", path.display())
+            )),
+        }
+    }
+
+    /// Return the chain of included files a key is nested in
+    pub fn include_chain(&self, key: &str) -> &[PathBuf] {
+        let mut prefix = key;
+
+        loop {
+            if let Some(chain) = self.includes.get(prefix) {
+                return chain;
+            }
+
+            match prefix.rfind('.') {
+                Some(idx) => prefix = &prefix[..idx],
+                None if !prefix.is_empty() => prefix = "",
+                None => return &[],
+            }
+        }
+    }
+
+    /// Directory of the configuration file a key was loaded from
+    pub fn config_dir(&self, key: &str) -> Option<&Path> {
+        self.include_chain(key).last()
+            .and_then(|x| x.parent())
+    }
+
+    /// Forget the include chains of a previous run, except for the root configuration
+    pub(crate) fn reset_includes(&mut self) {
+        self.includes.retain(|key, _| key.is_empty());
+    }
+
+    /// Record that the content of `path` is included at `key`
+    ///
+    /// Fails if `path` is already part of the include chain at this key.
+    pub fn include(&mut self, key: String, path: PathBuf) -> Result<()> {
+        let parent = self.include_chain(&key).to_vec();
+
+        self.include_from(key, parent, path)
+    }
+
+    /// Record that the content of `path` is included at `key` by the files in `chain`
+    pub(crate) fn include_from(&mut self, key: String, mut chain: Vec<PathBuf>, path: PathBuf) -> Result<()> {
+        let canonical = path.canonicalize()?;
+
+        let is_cycle = chain.iter()
+            .any(|x| x.canonicalize().map(|x| x == canonical).unwrap_or(false));
+
+        chain.push(path);
+
+        if is_cycle {
+            let chain = chain.iter()
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+
+            return Err(Error::IncludeCycle(chain));
+        }
+
+        self.includes.insert(key, chain);
+
+        Ok(())
+    }
+}
+
+/// Whether the `cmd` template may run commands
+#[derive(Clone, Debug, Default)]
+pub enum CommandPolicy {
+    #[default]
+    Allow,
+    Deny,
+    /// Only run commands which are exactly equal to one of the entries, with
+    /// the default `shell`, `cwd` and `env`
+    Allowlist(Vec<String>),
+}
+
+/// Restrictions for templates when loading configurations from untrusted sources
+///
+/// A violation fails the expansion with an error naming the offending key.
+///
+/// ```ignore
+/// let policy = Policy::default()
+///     .allow_command("git rev-parse --short HEAD")
+///     .root("conf");
+///
+/// let builder = TomlBuilder::from_file("conf/main.toml")?.policy(policy);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    commands: CommandPolicy,
+    roots: Option<Vec<PathBuf>>,
+}
+
+impl Policy {
+    /// Deny running commands and reading files outside of the working directory
+    pub fn safe() -> Policy {
+        Policy::default()
+            .deny_commands()
+            .root(".")
+    }
+
+    /// Disable the `cmd` template
+    pub fn deny_commands(mut self) -> Self {
+        self.commands = CommandPolicy::Deny;
+
+        self
+    }
+
+    /// Allow a single command, all other commands are denied
+    pub fn allow_command<T: Into<String>>(mut self, cmd: T) -> Self {
+        match self.commands {
+            CommandPolicy::Allowlist(ref mut allowed) => allowed.push(cmd.into()),
+            _ => self.commands = CommandPolicy::Allowlist(vec![cmd.into()]),
+        }
+
+        self
+    }
+
+    /// Restrict file access of templates to `root`, can be called multiple times
+    pub fn root<T: Into<PathBuf>>(mut self, root: T) -> Self {
+        self.roots.get_or_insert_with(Vec::new).push(root.into());
+
+        self
+    }
+}
+
+/// Which keys of a table are recognized as templates
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TemplateKeys {
+    /// Both `name` and `@name` expand the template
+    #[default]
+    Bare,
+    /// Only `@name` expands the template, `name` is an ordinary key
+    Prefixed,
+}
+
+impl TemplateKeys {
+    /// Return the key of template `name` in a table, if there is one
+    pub(crate) fn find(&self, map: &Table, name: &str) -> Option<String> {
+        let marked = format!("@{}", name);
+
+        match map.contains_key(&marked) {
+            true => Some(marked),
+            false if *self == TemplateKeys::Bare && map.contains_key(name) => Some(name.to_string()),
+            false => None,
+        }
+    }
+}
+
+/// Registered templates, tried in order for every table
+///
+/// A table expands a single template, they are tried in the order
+/// `from_file`, `cmd`, `glob`, `env`, `from_json`, `read_text`, `now`,
+/// `run_id`, `git`. Another template key in the same table is reported as
+/// [`Error::InvalidTemplate`], unless it is an unmarked option of the first one
+/// like `env` of `cmd`. Template keys in a table returned by a template are
+/// expanded as well. A template key can always be marked explicitly with an `@` prefix
+/// (`@cmd`), with [`TemplateKeys::Prefixed`] only marked keys are expanded.
+/// Keys starting with `@@` are escaped and appear without the `@@` in the
+/// result, so `@@cmd` becomes an ordinary `cmd` key.
+pub struct Templates {
+    templates: Vec<(String, DynTemplate)>,
+    pub(crate) keys: TemplateKeys,
+}
+
+/// Templates which are evaluated after all overrides, when references between
+/// values can be resolved
+pub(crate) const LATE_TEMPLATES: [&str; 3] = ["expr", "if", "switch"];
 
 impl Default for Templates {
     fn default() -> Templates {
-        Templates(HashMap::from([
-            ("from_file".to_string(), Box::new(FromFile::default()) as DynTemplate),
-            ("cmd".to_string(), Box::new(RunCommand::default()) as DynTemplate),
-            ("glob".to_string(), Box::new(GlobPattern::default()) as DynTemplate),
-        ]))
+        Templates {
+            templates: vec![
+                ("from_file".to_string(), Box::new(FromFile::default()) as DynTemplate),
+                ("cmd".to_string(), Box::new(RunCommand) as DynTemplate),
+                ("glob".to_string(), Box::new(GlobPattern) as DynTemplate),
+                ("env".to_string(), Box::new(EnvVar) as DynTemplate),
+                ("from_json".to_string(), Box::new(FromJson) as DynTemplate),
+                ("read_text".to_string(), Box::new(ReadText) as DynTemplate),
+                ("now".to_string(), Box::new(Now) as DynTemplate),
+                ("run_id".to_string(), Box::new(RunId) as DynTemplate),
+                ("git".to_string(), Box::new(GitInfo) as DynTemplate),
+            ],
+            keys: TemplateKeys::default(),
+        }
     }
 }
 
 impl Templates {
-    pub fn resolve(&mut self, root: toml::Value) -> (toml::Value, bool) {
+    /// Set which keys are recognized as templates
+    pub fn keys(&mut self, keys: TemplateKeys) {
+        self.keys = keys;
+    }
+
+    pub fn resolve(&mut self, ctx: &mut Context, root: toml::Value) -> Result<(toml::Value, bool)> {
+        self.resolve_key(ctx, String::new(), root)
+    }
+
+    /// Names of all templates, including those evaluated after overrides
+    pub(crate) fn names(&self) -> Vec<&str> {
+        self.templates.iter()
+            .map(|(name, _)| name.as_str())
+            .chain(LATE_TEMPLATES)
+            .collect()
+    }
+
+    fn resolve_key(&mut self, ctx: &mut Context, key: String, root: toml::Value) -> Result<(toml::Value, bool)> {
         let mut any_changed = false;
 
         let root = match root {
+            // templates in branches of conditionals are expanded after selection
+            toml::Value::Table(map) if LATE_TEMPLATES.iter().any(|x| self.keys.find(&map, x).is_some()) =>
+                toml::Value::Table(map),
             toml::Value::Table(mut map) => {
-                for (name, resolver) in self.0.iter_mut() {
-                    if let Some(value) = map.remove(name) {
+                let names = self.templates.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+                let keys = self.keys;
+
+                for (name, resolver) in self.templates.iter_mut() {
+                    if let Some((field, value)) = self.keys.find(&map, name).and_then(|x| map.remove(&x).map(|v| (x, v))) {
                         any_changed = true;
 
-                        match resolver.resolve("".into(), map, value) {
+                        let other = names.iter()
+                            .filter_map(|x| keys.find(&map, x))
+                            .find(|x| x.starts_with('@') || !resolver.options().contains(&x.as_str()));
+
+                        if let Some(other) = other {
+                            return Err(Error::InvalidTemplate(key, format!(
+                                "`{}` and `{}` cannot be expanded in the same table, keep one of them literally with `@@`",
+                                field, other
+                            )));
+                        }
+
+                        let mut args = map.clone();
+                        args.insert(field.clone(), value.clone());
+
+                        let value = match ctx.replay.as_mut().map(|x| x.take(&key, name, &args)) {
+                            Some(Some(recorded)) => {
+                                if ctx.verify_replay && Lock::verify(name) {
+                                    let live = resolver.resolve(ctx, key.clone(), map, value)
+                                        .map_err(|x| x.to_string());
+
+                                    if live.as_ref() != Ok(&recorded) {
+                                        ctx.mismatches.push(Mismatch {
+                                            key: key.clone(), template: name.clone(), recorded: recorded.clone(), live
+                                        });
+                                    }
+                                }
+
+                                recorded
+                            },
+                            Some(None) => return Err(Error::MissingLockEntry(key, name.clone())),
+                            None => resolver.resolve(ctx, key.clone(), map, value)?,
+                        };
+                        ctx.provenance.template(&key, &field, name, &value);
+                        ctx.outputs.push(TemplateOutput { key: key.clone(), name: name.clone(), args, value: value.clone() });
+
+                        match value {
                             Value::Table(new_map) => map = new_map,
-                            x => return (x, true)
+                            x => return Ok((x, true))
                         }
                     }
                 }
 
-                let map = map.into_iter()
-                    .map(|(k,v)| {
-                        let (val, changed) = self.resolve(v);
-                        any_changed |= changed;
+                let unknown = map.keys()
+                    .filter(|x| x.starts_with('@') && !x.starts_with("@@"))
+                    .find(|x| !LATE_TEMPLATES.contains(&&x[1..]));
+
+                if let Some(unknown) = unknown {
+                    return Err(Error::InvalidTemplate(key, format!("unknown template `{}`", unknown)));
+                }
 
-                        (k, val)
-                    })
-                    .collect();
+                let mut resolved = Table::new();
+                for (k, v) in map {
+                    let child_key = match key.is_empty() {
+                        true => k.clone(),
+                        false => format!("{}.{}", key, k),
+                    };
 
-                toml::Value::Table(map)
+                    let (val, changed) = self.resolve_key(ctx, child_key, v)?;
+                    any_changed |= changed;
+
+                    resolved.insert(k, val);
+                }
+
+                toml::Value::Table(resolved)
+            },
+            toml::Value::Array(elms) => {
+                let mut resolved = Vec::with_capacity(elms.len());
+                for (i, v) in elms.into_iter().enumerate() {
+                    let child_key = match key.is_empty() {
+                        true => i.to_string(),
+                        false => format!("{}.{}", key, i),
+                    };
+
+                    let (val, changed) = self.resolve_key(ctx, child_key, v)?;
+                    any_changed |= changed;
+
+                    resolved.push(val);
+                }
+
+                toml::Value::Array(resolved)
             },
             x => x,
         };
 
-        (root, any_changed)
+        Ok((root, any_changed))
     }
 }
 
-#[derive(Default)]
+/// Run a shell command and return its output
+///
+/// Command execution can be disabled at runtime with a [`Policy`] or compiled
+/// out by disabling the `cmd` feature.
+///
+/// Besides the command itself the table may contain `shell` (defaults to
+/// `bash`), `cwd`, `env` (table of additional variables), `timeout_secs`,
+/// `check` (fail on non-zero exit status, default true), `trim` (default
+/// true) and `type` to convert the output like the `env` template, for
+/// example `type = "toml"` to parse it as TOML. On timeout the command is
+/// killed together with all processes it started.
 pub struct RunCommand;
 
+impl RunCommand {
+    const OPTIONS: [&'static str; 7] = ["shell", "cwd", "env", "timeout_secs", "check", "trim", "type"];
+}
+
+#[cfg(not(feature = "cmd"))]
 impl Template for RunCommand {
-    fn resolve(&mut self, _key: String, _map: Table, field: toml::Value) -> Value {
+    fn resolve(&mut self, _ctx: &mut Context, key: String, _map: Table, _field: toml::Value) -> Result<Value> {
+        Err(Error::PolicyViolation(key, "command execution is disabled, enable the `cmd` feature".into()))
+    }
+
+    fn options(&self) -> &[&str] {
+        &Self::OPTIONS
+    }
+}
+
+#[cfg(feature = "cmd")]
+impl Template for RunCommand {
+    fn resolve(&mut self, ctx: &mut Context, key: String, map: Table, field: toml::Value) -> Result<Value> {
         let cmd = match field {
             Value::String(cmd) => cmd,
-            _ => panic!("Not a string as command")
+            _ => return Err(Error::InvalidTemplate(key, "command is not a string".into()))
+        };
+
+        ctx.check_command(&key, &cmd)?;
+
+        let options: CommandOptions = options(&key, Value::Table(map))?;
+        check_type(&key, &options.typ)?;
+
+        let changed = [
+            ("shell", options.shell != CommandOptions::default_shell()),
+            ("cwd", options.cwd.is_some()),
+            ("env", !options.env.is_empty()),
+        ];
+        for (option, _) in changed.iter().filter(|(_, changed)| *changed) {
+            ctx.check_command_option(&key, option)?;
+        }
+
+        if ctx.dry_run {
+            return Ok(Value::String(format!("<output of `{}`>", cmd)));
+        }
+
+        let mut command = Command::new(&options.shell);
+        command.arg("-c").arg(&cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        // own process group, so that a timeout also stops processes started by the command
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+        if let Some(cwd) = &options.cwd {
+            command.current_dir(cwd);
+        }
+
+        for (name, val) in &options.env {
+            let val = match val {
+                Value::String(s) => s.clone(),
+                x => x.to_string(),
+            };
+
+            command.env(name, val);
+        }
+
+        let mut child = command.spawn()?;
+
+        // drain pipes in the background, a full pipe would block the command
+        let mut stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+        let stdout = thread::spawn(move || {
+            let mut buf = Vec::new();
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+        let stderr = thread::spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        let timeout = options.timeout_secs.map(Duration::from_secs_f64);
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if timeout.map(|x| start.elapsed() > x).unwrap_or(false) {
+                // the readers are not joined, a process which left the group may still hold the pipes
+                kill(&mut child)?;
+                child.wait()?;
+
+                return Err(Error::CommandFailed(
+                    cmd,
+                    format!("timed out after {} seconds", options.timeout_secs.unwrap())
+                ));
+            }
+
+            thread::sleep(Duration::from_millis(5));
         };
 
-        let output = Command::new("/usr/bin/bash").arg("-c")
-            .arg(&cmd)
-            .output()
-            .unwrap();
+        let stdout = stdout.join().unwrap()?;
+        let stderr = stderr.join().unwrap()?;
+
+        if options.check && !status.success() {
+            return Err(Error::CommandFailed(
+                cmd,
+                format!("{}: {}", status, String::from_utf8_lossy(&stderr).trim())
+            ));
+        }
 
-        let stdout = String::from_utf8(output.stdout).unwrap().trim().to_string();
+        let mut stdout = String::from_utf8_lossy(&stdout).to_string();
+        if options.trim {
+            stdout = stdout.trim().to_string();
+        }
 
-        Value::String(stdout)
+        convert(&key, &stdout, &options.typ)?
+            .ok_or_else(|| Error::InvalidTemplate(
+                key,
+                format!("could not parse output \"{}\" of `{}` as {}", stdout, cmd, options.typ)
+            ))
+    }
+
+    fn options(&self) -> &[&str] {
+        &Self::OPTIONS
     }
 }
 
-#[derive(Default)]
+/// Kill a command together with all processes in its process group
+#[cfg(feature = "cmd")]
+fn kill(child: &mut Child) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+        return Ok(());
+    }
+
+    child.kill()
+}
+
+#[cfg(feature = "cmd")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandOptions {
+    #[serde(default = "CommandOptions::default_shell")]
+    shell: String,
+    cwd: Option<PathBuf>,
+    #[serde(default)]
+    env: Table,
+    timeout_secs: Option<f64>,
+    #[serde(default = "CommandOptions::enabled")]
+    check: bool,
+    #[serde(default = "CommandOptions::enabled")]
+    trim: bool,
+    #[serde(rename = "type", default = "CommandOptions::default_type")]
+    typ: String,
+}
+
+#[cfg(feature = "cmd")]
+impl CommandOptions {
+    fn default_shell() -> String {
+        "bash".into()
+    }
+
+    fn enabled() -> bool {
+        true
+    }
+
+    fn default_type() -> String {
+        "string".into()
+    }
+}
+
+/// List paths matching a glob pattern
+///
+/// Optional fields are `sort` (`name` or `mtime`, default `name`),
+/// `relative_to` (`cwd` or `config` for the directory of the configuration
+/// file containing the pattern), `files_only`, `exclude` (list of patterns
+/// matched relative to the base directory) and bounds `min` and `max` on the
+/// number of matches.
 pub struct GlobPattern;
 
 impl Template for GlobPattern {
-    fn resolve(&mut self, _key: String, _map: Table, field: toml::Value) -> Value {
+    fn resolve(&mut self, ctx: &mut Context, key: String, map: Table, field: toml::Value) -> Result<Value> {
         let pattern = match field {
             Value::String(cmd) => cmd,
-            _ => panic!("Glob pattern not a string!"),
+            _ => return Err(Error::InvalidTemplate(key, "glob pattern is not a string".into())),
         };
 
-        let path_list = glob::glob(&pattern).unwrap()
-            .filter_map(|x| x.ok())
+        let options: GlobOptions = options(&key, Value::Table(map))?;
+        let invalid = |err: &dyn std::fmt::Display| Error::InvalidTemplate(key.clone(), err.to_string());
+
+        let base = match options.relative_to.as_str() {
+            "cwd" => None,
+            "config" => {
+                let dir = ctx.config_dir(&key)
+                    .ok_or_else(|| invalid(&"pattern is relative to config, but not loaded from a file"))?;
+
+                Some(dir.to_path_buf())
+            },
+            x => return Err(invalid(&format!("unknown base `{}`, expected `cwd` or `config`", x))),
+        };
+
+        // the directory may contain characters with a special meaning in patterns
+        let full_pattern = match &base {
+            Some(dir) => Path::new(&glob::Pattern::escape(&dir.display().to_string()))
+                .join(&pattern)
+                .display()
+                .to_string(),
+            None => pattern.clone(),
+        };
+
+        let exclude = options.exclude.iter()
+            .map(|x| glob::Pattern::new(x).map_err(|err| invalid(&err)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut paths = Vec::new();
+        for path in glob::glob(&full_pattern).map_err(|err| invalid(&err))? {
+            let path = path.map_err(|err| invalid(&err))?;
+
+            if options.files_only && !path.is_file() {
+                continue;
+            }
+            let relative = base.as_ref()
+                .and_then(|x| path.strip_prefix(x).ok())
+                .unwrap_or(&path);
+            if exclude.iter().any(|x| x.matches_path(relative)) {
+                continue;
+            }
+
+            ctx.check_path(&key, &path)?;
+            paths.push(path);
+        }
+
+        match options.sort.as_str() {
+            "name" => paths.sort(),
+            "mtime" => {
+                let mut with_mtime = paths.into_iter()
+                    .map(|x| Ok((x.metadata()?.modified()?, x)))
+                    .collect::<Result<Vec<_>>>()?;
+                with_mtime.sort();
+
+                paths = with_mtime.into_iter().map(|x| x.1).collect();
+            },
+            x => return Err(invalid(&format!("unknown sort order `{}`, expected `name` or `mtime`", x))),
+        }
+
+        if paths.len() < options.min {
+            return Err(invalid(&format!("`{}` matched {} paths, expected at least {}", pattern, paths.len(), options.min)));
+        }
+        if options.max.map(|x| paths.len() > x).unwrap_or(false) {
+            return Err(invalid(&format!("`{}` matched {} paths, expected at most {}", pattern, paths.len(), options.max.unwrap())));
+        }
+
+        let path_list = paths.into_iter()
             .map(|x| Value::String(x.display().to_string()))
-            .collect::<Vec<_>>();
+            .collect();
 
-        // override current node with content of file
-        Value::Array(path_list)
+        // override current node with the list of paths
+        Ok(Value::Array(path_list))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GlobOptions {
+    #[serde(default = "GlobOptions::default_sort")]
+    sort: String,
+    #[serde(default = "GlobOptions::default_relative_to")]
+    relative_to: String,
+    #[serde(default)]
+    min: usize,
+    max: Option<usize>,
+    #[serde(default)]
+    files_only: bool,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+impl GlobOptions {
+    fn default_sort() -> String {
+        "name".into()
+    }
+
+    fn default_relative_to() -> String {
+        "cwd".into()
+    }
+}
+
+/// Remove the `@@` escape of keys after all templates are expanded
+pub(crate) fn unescape(root: Value) -> Value {
+    match root {
+        Value::Table(map) => Value::Table(
+            map.into_iter()
+                .map(|(k, v)| match k.strip_prefix("@@") {
+                    Some(stripped) => (stripped.to_string(), unescape(v)),
+                    None => (k, unescape(v)),
+                })
+                .collect()
+        ),
+        Value::Array(elms) => Value::Array(elms.into_iter().map(unescape).collect()),
+        x => x,
+    }
+}
+
+/// Escape a resolved configuration, the inverse of [`unescape`] and interpolation
+///
+/// Keys in `names` or starting with `@` get a `@@` prefix and `${` in strings
+/// becomes `\${`, so that loading the result gives the same values again.
+pub(crate) fn escape(root: &Value, names: &[&str]) -> Value {
+    match root {
+        Value::Table(map) => Value::Table(
+            map.iter()
+                .map(|(k, v)| match k.starts_with('@') || names.contains(&k.as_str()) {
+                    true => (format!("@@{}", k), escape(v, names)),
+                    false => (k.clone(), escape(v, names)),
+                })
+                .collect()
+        ),
+        Value::Array(elms) => Value::Array(elms.iter().map(|x| escape(x, names)).collect()),
+        Value::String(s) => Value::String(s.replace("${", "\\${")),
+        x => x.clone(),
     }
 }
 
+/// Parse a single TOML value, for example `64`, `true` or `{ a = 1 }`
+pub(crate) fn parse_value(val: &str) -> std::result::Result<Value, toml::de::Error> {
+    let mut table: Table = toml::from_str(&format!("value = {}", val))?;
+
+    Ok(table.remove("value").unwrap())
+}
+
+/// Convert a string to a TOML value of type `typ`
+///
+/// Supported types are `string`, `integer`, `float`, `bool`, `table`, `toml`
+/// for either a single value or a whole document and `auto`, which parses
+/// a TOML value and falls back to a string.
+///
+/// Returns `None` if the value can not be parsed and fails on unknown types.
+pub(crate) fn convert(key: &str, val: &str, typ: &str) -> Result<Option<Value>> {
+    check_type(key, typ)?;
+
+    Ok(match typ {
+        "string" => Some(Value::String(val.to_string())),
+        "integer" => val.trim().parse().ok().map(Value::Integer),
+        "float" => val.trim().parse().ok().map(Value::Float),
+        "bool" | "boolean" => val.trim().parse().ok().map(Value::Boolean),
+        "table" => parse_value(val).ok().filter(|x| x.is_table()),
+        "toml" => parse_value(val).or_else(|_| val.parse()).ok(),
+        _ => Some(parse_value(val).unwrap_or_else(|_| Value::String(val.to_string()))),
+    })
+}
+
+/// Fail if `typ` is not supported by [`convert`]
+pub(crate) fn check_type(key: &str, typ: &str) -> Result<()> {
+    match typ {
+        "string" | "integer" | "float" | "bool" | "boolean" | "table" | "toml" | "auto" => Ok(()),
+        x => Err(Error::InvalidTemplate(
+            key.into(),
+            format!("unknown type `{}`, expected `string`, `integer`, `float`, `bool`, `table`, `toml` or `auto`", x)
+        )),
+    }
+}
+
+/// Read an environment variable and convert it to a TOML value
+///
+/// The type is given by the `type` field (`string`, `integer`, `float`,
+/// `bool`, `table` or `auto`) and otherwise inferred from `default`.
+pub struct EnvVar;
+
+impl Template for EnvVar {
+    fn resolve(&mut self, _ctx: &mut Context, key: String, mut map: Table, field: toml::Value) -> Result<Value> {
+        let name = match field {
+            Value::String(name) => name,
+            _ => return Err(Error::InvalidTemplate(key, "environment variable name is not a string".into())),
+        };
+
+        let default = map.remove("default");
+        let typ = match map.remove("type") {
+            Some(Value::String(typ)) => typ,
+            Some(_) => return Err(Error::InvalidTemplate(key, "type of environment variable is not a string".into())),
+            None => match default.as_ref().map(|x| x.type_str()) {
+                // arrays and datetimes are parsed as TOML values
+                Some("array") | Some("datetime") => "toml".into(),
+                Some(typ) => typ.into(),
+                None => "string".into(),
+            },
+        };
+
+        check_type(&key, &typ)?;
+
+        let val = match (env::var(&name), default) {
+            (Ok(val), _) => val,
+            (Err(_), Some(default)) => return Ok(default),
+            (Err(_), None) => return Err(Error::MissingEnv(name, key)),
+        };
+
+        convert(&key, &val, &typ)?.ok_or_else(|| Error::InvalidTemplate(
+            key,
+            format!("could not parse ${} = \"{}\" as {}", name, val, typ)
+        ))
+    }
+}
+
+/// Include configuration files from a directory of choices
+///
+/// The file `base_path/name.toml` replaces the table. If `name` is missing the
+/// `default` choice is used, with `names = [...]` several files are merged in
+/// order. Unknown names are reported together with the available choices.
+///
+/// A table with only a `default` has no `name` key to override, select another
+/// choice with `++key.from_file.name=...` instead.
 #[derive(Default)]
 pub struct FromFile {
     base_path: HashMap<String, PathBuf>,
 }
 
-impl Template for FromFile {
-    fn resolve(&mut self, key: String, _map: Table, field: toml::Value) -> Value {
-        let field: FromFileField = field.try_into().unwrap();
+impl FromFile {
+    /// List the names of all configuration files in `base_path`
+    fn choices(base_path: &Path) -> Result<Vec<String>> {
+        let mut choices = std::fs::read_dir(base_path)?
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.extension().map(|x| x == "toml").unwrap_or(false))
+            .filter_map(|x| x.file_stem().map(|x| x.to_string_lossy().to_string()))
+            .collect::<Vec<_>>();
+        choices.sort();
+
+        Ok(choices)
+    }
+
+    /// Load `name` at `key`, which is included by the files in `parent`
+    fn load(ctx: &mut Context, key: &str, parent: &[PathBuf], base_path: &Path, name: &str) -> Result<Value> {
+        let path = base_path.join(format!("{}.toml", name));
+
+        if !path.is_file() {
+            let available = Self::choices(base_path)?;
+            let suggestion = crate::suggest::closest(name, available.iter().map(|x| x.as_str()))
+                .map(|x| x.to_string());
+
+            return Err(Error::UnknownChoice {
+                key: key.into(),
+                name: name.into(),
+                base_path: base_path.display().to_string(),
+                available,
+                suggestion,
+            });
+        }
 
-        let mut f = File::open(field.base_path.join(&format!("{}.toml", field.name))).unwrap();
+        ctx.check_path(key, &path)?;
+        ctx.include_from(key.into(), parent.to_vec(), path.clone())?;
+
+        let mut f = File::open(&path)?;
         let mut content = String::new();
-        f.read_to_string(&mut content).unwrap();
+        f.read_to_string(&mut content)?;
+
+        let value = content.parse::<toml::Value>()?;
+        ctx.provenance.loaded(key, &value, &Source::file(path, key.into(), &content));
+
+        Ok(value)
+    }
+}
+
+/// Merge table `b` into `a`, values of `b` take precedence
+fn merge_tables(a: &mut Table, b: Table) {
+    for (k, v) in b {
+        match (a.get_mut(&k), v) {
+            (Some(Value::Table(a)), Value::Table(b)) => merge_tables(a, b),
+            (_, v) => { a.insert(k, v); },
+        }
+    }
+}
+
+impl Template for FromFile {
+    fn resolve(&mut self, ctx: &mut Context, key: String, _map: Table, field: toml::Value) -> Result<Value> {
+        let field: FromFileField = options(&key, field)?;
+        // the available choices are listed on errors, which must not reveal other directories
+        ctx.check_path(&key, &field.base_path)?;
+
+        let names = match (field.names, field.name.or(field.default)) {
+            (Some(names), _) => names,
+            (None, Some(name)) => vec![name],
+            (None, None) => return Err(Error::InvalidTemplate(
+                key,
+                format!("no `name` given, available are [{}]", Self::choices(&field.base_path)?.join(", "))
+            )),
+        };
+
+        // every file is included by the same parent, not by the files merged before it
+        let parent = ctx.include_chain(&key).to_vec();
+
+        let mut merged = Table::new();
+        for name in names {
+            match Self::load(ctx, &key, &parent, &field.base_path, &name)? {
+                Value::Table(content) => merge_tables(&mut merged, content),
+                _ => unreachable!(),
+            }
+        }
 
         self.base_path.insert(key, field.base_path);
 
         // override current node with content of file
-        content.parse::<toml::Value>().unwrap()
+        Ok(Value::Table(merged))
     }
 }
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct FromFileField {
     base_path: PathBuf,
-    name: String
+    name: Option<String>,
+    default: Option<String>,
+    names: Option<Vec<String>>,
+}
+
+/// Deserialize the options of the template at `key`
+fn options<T: serde::de::DeserializeOwned>(key: &str, val: Value) -> Result<T> {
+    val.try_into().map_err(|x: toml::de::Error| Error::InvalidTemplate(key.into(), x.to_string()))
+}
+
+/// Read a file to string after checking it against the policy
+fn read_file(ctx: &Context, key: &str, path: &Path) -> Result<String> {
+    ctx.check_path(key, path)?;
+
+    let mut f = File::open(path)?;
+    let mut content = String::new();
+    f.read_to_string(&mut content)?;
+
+    Ok(content)
+}
+
+/// Convert a JSON document to a TOML subtree
+///
+/// TOML has no null, by default nulls are skipped in objects and arrays. Set
+/// `nulls = "error"` to reject them or `null_value = ...` to replace them.
+/// An optional JSON `pointer` (e.g. `"/model/vocab_size"`) selects a part of
+/// the document.
+pub struct FromJson;
+
+/// Handling of JSON nulls, which have no TOML equivalent
+enum Nulls {
+    Skip,
+    Error,
+    Replace(Value),
+}
+
+impl FromJson {
+    fn convert(key: &str, val: serde_json::Value, nulls: &Nulls) -> Result<Option<Value>> {
+        use serde_json::Value as Json;
+
+        let val = match val {
+            Json::Null => return match nulls {
+                Nulls::Skip => Ok(None),
+                Nulls::Error => Err(Error::InvalidTemplate(
+                    key.into(),
+                    "JSON document contains null".into()
+                )),
+                Nulls::Replace(val) => Ok(Some(val.clone())),
+            },
+            Json::Bool(b) => Value::Boolean(b),
+            Json::Number(n) => match (n.as_i64(), n.as_f64()) {
+                (Some(i), _) => Value::Integer(i),
+                (None, Some(f)) if n.is_f64() => Value::Float(f),
+                _ => return Err(Error::InvalidTemplate(key.into(), format!("JSON number {} does not fit into TOML", n))),
+            },
+            Json::String(s) => Value::String(s),
+            Json::Array(elms) => {
+                let mut arr = Vec::new();
+                for elm in elms {
+                    arr.extend(Self::convert(key, elm, nulls)?);
+                }
+
+                Value::Array(arr)
+            },
+            Json::Object(obj) => {
+                let mut table = Table::new();
+                for (k, v) in obj {
+                    if let Some(v) = Self::convert(key, v, nulls)? {
+                        table.insert(k, v);
+                    }
+                }
+
+                Value::Table(table)
+            },
+        };
+
+        Ok(Some(val))
+    }
+}
+
+impl Template for FromJson {
+    fn resolve(&mut self, ctx: &mut Context, key: String, map: Table, field: toml::Value) -> Result<Value> {
+        let path = match field {
+            Value::String(path) => PathBuf::from(path),
+            _ => return Err(Error::InvalidTemplate(key, "JSON path is not a string".into())),
+        };
+
+        let options: JsonOptions = options(&key, Value::Table(map))?;
+        let nulls = match (options.null_value, options.nulls.as_deref()) {
+            (Some(val), None) => Nulls::Replace(val),
+            (Some(_), Some(_)) => return Err(Error::InvalidTemplate(key, "either give `nulls` or `null_value`".into())),
+            (None, None) | (None, Some("skip")) => Nulls::Skip,
+            (None, Some("error")) => Nulls::Error,
+            (None, Some(x)) => return Err(Error::InvalidTemplate(key, format!("unknown null handling `{}`, expected `skip` or `error`", x))),
+        };
+
+        let content = read_file(ctx, &key, &path)?;
+        let mut json: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|err| Error::InvalidTemplate(key.clone(), format!("could not parse {}: {}", path.display(), err)))?;
+
+        if let Some(pointer) = options.pointer {
+            json = json.pointer(&pointer).cloned()
+                .ok_or_else(|| Error::InvalidTemplate(key.clone(), format!("{} has no value at `{}`", path.display(), pointer)))?;
+        }
+
+        Self::convert(&key, json, &nulls)?
+            .ok_or_else(|| Error::InvalidTemplate(key, "JSON document is null".into()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonOptions {
+    nulls: Option<String>,
+    null_value: Option<Value>,
+    pointer: Option<String>,
+}
+
+/// Read a plain text file, optional fields are `trim` (default true) and
+/// `type` to convert the content like the `env` template
+pub struct ReadText;
+
+impl Template for ReadText {
+    fn resolve(&mut self, ctx: &mut Context, key: String, map: Table, field: toml::Value) -> Result<Value> {
+        let path = match field {
+            Value::String(path) => PathBuf::from(path),
+            _ => return Err(Error::InvalidTemplate(key, "text file path is not a string".into())),
+        };
+
+        let options: TextOptions = options(&key, Value::Table(map))?;
+        check_type(&key, &options.typ)?;
+
+        let mut content = read_file(ctx, &key, &path)?;
+        if options.trim {
+            content = content.trim().to_string();
+        }
+
+        convert(&key, &content, &options.typ)?.ok_or_else(|| Error::InvalidTemplate(
+            key,
+            format!("could not parse content of {} as {}", path.display(), options.typ)
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextOptions {
+    #[serde(default = "TextOptions::default_trim")]
+    trim: bool,
+    #[serde(rename = "type", default = "TextOptions::default_type")]
+    typ: String,
+}
+
+impl TextOptions {
+    fn default_trim() -> bool {
+        true
+    }
+
+    fn default_type() -> String {
+        "string".into()
+    }
+}
+
+/// Format the start time of the run, for example `{ now = "%Y-%m-%d_%H-%M-%S" }`
+///
+/// The time is in the local timezone unless `utc = true` is given.
+pub struct Now;
+
+impl Template for Now {
+    fn resolve(&mut self, ctx: &mut Context, key: String, mut map: Table, field: toml::Value) -> Result<Value> {
+        let format = match field {
+            Value::String(format) => format,
+            _ => return Err(Error::InvalidTemplate(key, "time format is not a string".into())),
+        };
+
+        let utc = match map.remove("utc") {
+            Some(Value::Boolean(utc)) => utc,
+            None => false,
+            Some(_) => return Err(Error::InvalidTemplate(key, "`utc` is not a boolean".into())),
+        };
+
+        let started = ctx.run().started;
+        let items = chrono::format::StrftimeItems::new(&format).collect::<Vec<_>>();
+        if items.contains(&chrono::format::Item::Error) {
+            return Err(Error::InvalidTemplate(key, format!("invalid time format `{}`", format)));
+        }
+
+        let formatted = match utc {
+            true => started.with_timezone(&Utc).format_with_items(items.into_iter()).to_string(),
+            false => started.format_with_items(items.into_iter()).to_string(),
+        };
+
+        Ok(Value::String(formatted))
+    }
+}
+
+/// Identifier of the run
+///
+/// `short` and `long` give 8 or 32 random hex digits, which are the same for
+/// all templates of a run and can be made reproducible with a seed. `counter`
+/// increments a number stored in `file` (default `.run_counter`) and formats it
+/// with `width` digits.
+pub struct RunId;
+
+impl Template for RunId {
+    fn resolve(&mut self, ctx: &mut Context, key: String, mut map: Table, field: toml::Value) -> Result<Value> {
+        let kind = match field {
+            Value::String(kind) => kind,
+            _ => return Err(Error::InvalidTemplate(key, "kind of run identifier is not a string".into())),
+        };
+
+        let id = format!("{:032x}", ctx.run().id);
+        match kind.as_str() {
+            "short" => Ok(Value::String(id[..8].to_string())),
+            "long" => Ok(Value::String(id)),
+            "counter" => {
+                let file = match map.remove("file") {
+                    Some(Value::String(file)) => PathBuf::from(file),
+                    None => PathBuf::from(".run_counter"),
+                    Some(_) => return Err(Error::InvalidTemplate(key, "counter `file` is not a string".into())),
+                };
+                let width = match map.remove("width") {
+                    Some(Value::Integer(width)) => usize::try_from(width)
+                        .map_err(|_| Error::InvalidTemplate(key.clone(), format!("`width` of {} is negative", width)))?,
+                    None => 0,
+                    Some(_) => return Err(Error::InvalidTemplate(key, "`width` is not an integer".into())),
+                };
+
+                let count = match ctx.run().counters.get(&file) {
+                    Some(count) => *count,
+                    None => {
+                        ctx.check_path(&key, &file)?;
+
+                        let count = match std::fs::read_to_string(&file) {
+                            Ok(content) => content.trim().parse::<u64>()
+                                .map_err(|_| Error::InvalidTemplate(key.clone(), format!("invalid counter in {}", file.display())))?
+                                + 1,
+                            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+                            Err(err) => return Err(err.into()),
+                        };
+
+                        if !ctx.dry_run {
+                            std::fs::write(&file, count.to_string())?;
+                        }
+                        ctx.run().counters.insert(file, count);
+
+                        count
+                    },
+                };
+
+                Ok(Value::String(format!("{:0width$}", count, width = width)))
+            },
+            x => Err(Error::InvalidTemplate(key, format!("unknown run identifier `{}`, expected `short`, `long` or `counter`", x))),
+        }
+    }
+}
+
+/// Metadata of the git repository containing the configuration
+///
+/// One of `rev`, `short_rev`, `branch`, `dirty` or `describe`. The repository
+/// is found by walking up from `path`, or the directory of the configuration
+/// file. It is read from disk, so no `git` binary is required. If the
+/// repository can not be read, `fallback` is returned when given.
+pub struct GitInfo;
+
+impl Template for GitInfo {
+    fn resolve(&mut self, ctx: &mut Context, key: String, mut map: Table, field: toml::Value) -> Result<Value> {
+        let kind = match field {
+            Value::String(kind) => kind,
+            _ => return Err(Error::InvalidTemplate(key, "git information is not a string".into())),
+        };
+        if !["rev", "short_rev", "branch", "dirty", "describe"].contains(&kind.as_str()) {
+            return Err(Error::InvalidTemplate(
+                key,
+                format!("unknown git information `{}`, expected `rev`, `short_rev`, `branch`, `dirty` or `describe`", kind)
+            ));
+        }
+
+        let start = match map.remove("path") {
+            Some(Value::String(path)) => PathBuf::from(path),
+            None => match ctx.config_dir(&key) {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+            Some(_) => return Err(Error::InvalidTemplate(key, "`path` is not a string".into())),
+        };
+        let fallback = map.remove("fallback");
+
+        let info = crate::git::Repository::discover(&start).and_then(|repo| {
+            ctx.check_path(&key, repo.git_dir())?;
+
+            match kind.as_str() {
+                "rev" => repo.rev().map(Value::String),
+                "short_rev" => repo.rev().map(|x| Value::String(x[..7].to_string())),
+                "branch" => repo.head()?.0
+                    .map(Value::String)
+                    .ok_or_else(|| Error::Git("HEAD is detached".into())),
+                "dirty" => repo.is_dirty().map(Value::Boolean),
+                _ => repo.describe().map(Value::String),
+            }
+        });
+
+        match (info, fallback) {
+            (Err(Error::PolicyViolation(key, msg)), _) => Err(Error::PolicyViolation(key, msg)),
+            (Err(_), Some(fallback)) => Ok(fallback),
+            (info, _) => info,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(content: &str) -> Result<Value> {
+        let root = content.parse()?;
+        let (root, _) = Templates::default().resolve(&mut Context::default(), root)?;
+
+        Ok(root)
+    }
+
+    #[test]
+    fn test_template_keys() {
+        let content = r#"
+            [tool]
+            "@@cmd" = "make"
+            [rev]
+            "@env" = "TSAP_TEST_UNSET"
+            default = "none"
+            [other]
+            env = "TSAP_TEST_UNSET"
+            default = "none"
+        "#;
+
+        let root = unescape(resolve(content).unwrap());
+        assert_eq!(root["tool"]["cmd"].as_str(), Some("make"));
+        assert_eq!(root["rev"].as_str(), Some("none"));
+        assert_eq!(root["other"].as_str(), Some("none"));
+
+        let mut templates = Templates::default();
+        templates.keys(TemplateKeys::Prefixed);
+        let (root, _) = templates.resolve(&mut Context::default(), content.parse().unwrap()).unwrap();
+        assert_eq!(root["rev"].as_str(), Some("none"));
+        assert_eq!(root["other"]["env"].as_str(), Some("TSAP_TEST_UNSET"));
+
+        assert!(matches!(resolve("x = { '@nope' = 1 }"), Err(Error::InvalidTemplate(..))));
+    }
+
+    #[test]
+    fn test_env() {
+        env::set_var("TSAP_TEST_WORKERS", "8");
+        env::set_var("TSAP_TEST_AUGMENT", "{ flip = true }");
+
+        let root = resolve(r#"
+            workers = { env = "TSAP_TEST_WORKERS", default = 1 }
+            name = { env = "TSAP_TEST_WORKERS" }
+            augment = { env = "TSAP_TEST_AUGMENT", type = "table" }
+            data = { env = "TSAP_TEST_UNSET", default = "/data" }
+        "#).unwrap();
+
+        assert_eq!(root["workers"].as_integer(), Some(8));
+        assert_eq!(root["name"].as_str(), Some("8"));
+        assert_eq!(root["augment"]["flip"].as_bool(), Some(true));
+        assert_eq!(root["data"].as_str(), Some("/data"));
+
+        assert!(matches!(resolve("data = { env = 'TSAP_TEST_UNSET' }"), Err(Error::MissingEnv(..))));
+        assert!(matches!(resolve("data = { env = 'TSAP_TEST_UNSET', type = 'path', default = '/data' }"), Err(Error::InvalidTemplate(..))));
+
+        // a second template is not silently dropped, unless it is kept literally
+        assert!(matches!(resolve("data = { env = 'TSAP_TEST_WORKERS', now = '%Y' }"), Err(Error::InvalidTemplate(..))));
+        assert!(matches!(resolve("data = { env = 'TSAP_TEST_WORKERS', '@@now' = '%Y' }"), Ok(_)));
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut ctx = Context::default();
+
+        #[cfg(feature = "cmd")]
+        {
+            ctx.policy = Policy::default().allow_command("echo allowed");
+            let root: Value = "a = { cmd = 'echo allowed' }\nb = { cmd = 'echo denied' }".parse().unwrap();
+
+            match Templates::default().resolve(&mut ctx, root) {
+                Err(Error::PolicyViolation(key, _)) => assert_eq!(key, "b"),
+                x => panic!("expected policy violation, got {:?}", x),
+            }
+
+            // the allowlist does not cover another shell, directory or environment
+            for option in ["shell = 'sh'", "cwd = '/'", "env = { PATH = '.' }"] {
+                for policy in [Policy::default().allow_command("echo allowed"), Policy::default().deny_commands()] {
+                    ctx.policy = policy;
+                    let root: Value = format!("a = {{ cmd = 'echo allowed', {} }}", option).parse().unwrap();
+
+                    match Templates::default().resolve(&mut ctx, root) {
+                        Err(Error::PolicyViolation(key, _)) => assert_eq!(key, "a"),
+                        x => panic!("expected policy violation for {}, got {:?}", option, x),
+                    }
+                }
+            }
+        }
+
+        ctx.policy = Policy::safe();
+        let root: Value = "files = { glob = '/*' }".parse().unwrap();
+        match Templates::default().resolve(&mut ctx, root) {
+            Err(Error::PolicyViolation(key, _)) => assert_eq!(key, "files"),
+            x => panic!("expected policy violation, got {:?}", x),
+        }
+
+        let root: Value = format!("model = {{ from_file = {{ base_path = '{}', name = 'nope' }} }}", env::temp_dir().display())
+            .parse().unwrap();
+        match Templates::default().resolve(&mut ctx, root) {
+            Err(Error::PolicyViolation(key, _)) => assert_eq!(key, "model"),
+            x => panic!("expected policy violation, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        let dir = env::temp_dir().join("tsap_tests").join("glob");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("shard_c")).unwrap();
+        for name in ["shard_b.wav", "shard_a.wav", "shard_x.wav", "main.toml"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+
+        let mut ctx = Context::default();
+        ctx.include(String::new(), dir.join("main.toml")).unwrap();
+        let root: Value = r#"
+            [data]
+            shards = { glob = "shard_*", relative_to = "config", files_only = true, exclude = ["shard_x.wav"], max = 2 }
+        "#.parse().unwrap();
+
+        let (root, _) = Templates::default().resolve(&mut ctx, root).unwrap();
+        let shards = root["data"]["shards"].as_array().unwrap().iter()
+            .map(|x| x.as_str().unwrap().rsplit('/').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(shards, ["shard_a.wav", "shard_b.wav"]);
+
+        let root: Value = "shards = { glob = 'missing_*', relative_to = 'config', min = 1 }".parse().unwrap();
+        match Templates::default().resolve(&mut ctx, root) {
+            Err(Error::InvalidTemplate(key, msg)) => {
+                assert_eq!(key, "shards");
+                assert!(msg.contains("at least 1"));
+            },
+            x => panic!("expected cardinality error, got {:?}", x),
+        }
+
+        // special characters in the configuration directory are no pattern
+        let dir = dir.join("run[1]");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("shard.wav"), "").unwrap();
+        std::fs::write(dir.join("main.toml"), "").unwrap();
+
+        let mut ctx = Context::default();
+        ctx.include(String::new(), dir.join("main.toml")).unwrap();
+        let root: Value = "shards = { glob = '*.wav', relative_to = 'config', min = 1 }".parse().unwrap();
+        let (root, _) = Templates::default().resolve(&mut ctx, root).unwrap();
+        assert_eq!(root["shards"].as_array().map(|x| x.len()), Some(1));
+    }
+
+    #[test]
+    fn test_from_file_choices() {
+        let dir = env::temp_dir().join("tsap_tests").join("choices");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("gru.toml"), "hidden = 128\n[opt]\nlr = 0.1").unwrap();
+        std::fs::write(dir.join("bcresnet.toml"), "width = 2").unwrap();
+        std::fs::write(dir.join("small.toml"), "hidden = 32\n[opt]\nmomentum = 0.9").unwrap();
+        let base = dir.display();
+
+        let root = resolve(&format!("model = {{ from_file = {{ base_path = '{}', default = 'gru' }} }}", base)).unwrap();
+        assert_eq!(root["model"]["hidden"].as_integer(), Some(128));
+
+        let root = resolve(&format!("model = {{ from_file = {{ base_path = '{}', names = ['gru', 'small'] }} }}", base)).unwrap();
+        assert_eq!(root["model"]["hidden"].as_integer(), Some(32));
+        assert_eq!(root["model"]["opt"]["lr"].as_float(), Some(0.1));
+        assert_eq!(root["model"]["opt"]["momentum"].as_float(), Some(0.9));
+
+        // both files are included by the configuration, `gru` including `small` is no cycle
+        std::fs::write(dir.join("gru.toml"), format!("hidden = 128\nextra = {{ from_file = {{ base_path = '{}', name = 'small' }} }}", base)).unwrap();
+        let root = resolve(&format!("model = {{ from_file = {{ base_path = '{}', names = ['small', 'gru'] }} }}", base)).unwrap();
+        assert_eq!(root["model"]["extra"]["hidden"].as_integer(), Some(32));
+
+        match resolve(&format!("model = {{ from_file = {{ base_path = '{}', name = 'gur' }} }}", base)) {
+            Err(err @ Error::UnknownChoice { .. }) => {
+                let msg = err.to_string();
+                assert!(msg.contains("[bcresnet, gru, small]"));
+                assert!(msg.contains("did you mean `gru`?"));
+            },
+            x => panic!("expected unknown choice, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_json_and_text() {
+        let dir = env::temp_dir().join("tsap_tests").join("json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("meta.json"), r#"{"vocab_size": 512, "scale": 0.5, "bias": null, "tags": ["a", null]}"#).unwrap();
+        std::fs::write(dir.join("VERSION"), "1.2.0\n").unwrap();
+
+        let root = resolve(&format!(r#"
+            meta = {{ from_json = "{0}/meta.json" }}
+            vocab = {{ from_json = "{0}/meta.json", pointer = "/vocab_size" }}
+            replaced = {{ from_json = "{0}/meta.json", null_value = 0 }}
+            version = {{ read_text = "{0}/VERSION" }}
+        "#, dir.display())).unwrap();
+
+        assert_eq!(root["meta"]["vocab_size"].as_integer(), Some(512));
+        assert!(root["meta"].get("bias").is_none());
+        assert_eq!(root["meta"]["tags"].as_array().unwrap().len(), 1);
+        assert_eq!(root["vocab"].as_integer(), Some(512));
+        assert_eq!(root["replaced"]["bias"].as_integer(), Some(0));
+        assert_eq!(root["version"].as_str(), Some("1.2.0"));
+
+        let res = resolve(&format!("meta = {{ from_json = '{}/meta.json', nulls = 'error' }}", dir.display()));
+        assert!(matches!(res, Err(Error::InvalidTemplate(..))));
+
+        let res = resolve(&format!("version = {{ read_text = '{}/VERSION', strip = true }}", dir.display()));
+        assert!(matches!(res, Err(Error::InvalidTemplate(key, msg)) if key == "version" && msg.contains("unknown field `strip`")));
+    }
+
+    #[test]
+    fn test_run_templates() {
+        let counter = env::temp_dir().join("tsap_tests").join("run_counter");
+        std::fs::create_dir_all(counter.parent().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&counter);
+
+        let root: Value = format!(r#"
+            date = {{ now = "%Y-%m-%d" }}
+            stamp = {{ now = "%s", utc = true }}
+            id = {{ run_id = "short" }}
+            long_id = {{ run_id = "long" }}
+            num = {{ run_id = "counter", file = "{0}", width = 3 }}
+            num2 = {{ run_id = "counter", file = "{0}", width = 3 }}
+        "#, counter.display()).parse().unwrap();
+
+        let mut ctx = Context { seed: Some(42), ..Context::default() };
+        ctx.start_run();
+        let (first, _) = Templates::default().resolve(&mut ctx, root.clone()).unwrap();
+        assert_eq!(first["date"].as_str().unwrap().len(), 10);
+        assert!(first["long_id"].as_str().unwrap().starts_with(first["id"].as_str().unwrap()));
+        assert_eq!(first["num"].as_str(), Some("000"));
+        assert_eq!(first["num2"].as_str(), Some("000"));
+
+        ctx.start_run();
+        let (second, _) = Templates::default().resolve(&mut ctx, root).unwrap();
+        assert_eq!(first["id"], second["id"]);
+        assert_eq!(second["num"].as_str(), Some("001"));
+
+        let root: Value = "num = { run_id = 'counter', width = -1 }".parse().unwrap();
+        assert!(matches!(Templates::default().resolve(&mut ctx, root), Err(Error::InvalidTemplate(..))));
+    }
+
+    #[test]
+    fn test_git() {
+        let root = resolve(r#"
+            rev = { git = "rev", path = "/", fallback = "unknown" }
+        "#).unwrap();
+        assert_eq!(root["rev"].as_str(), Some("unknown"));
+
+        assert!(matches!(resolve("rev = { git = 'rev', path = '/' }"), Err(Error::Git(..))));
+        assert!(matches!(resolve("rev = { git = 'hash' }"), Err(Error::InvalidTemplate(..))));
+    }
+
+    #[cfg(feature = "cmd")]
+    #[test]
+    fn test_cmd() {
+        let root = resolve(r#"
+            rev = { cmd = "echo $REV", shell = "sh", env = { REV = "abc" } }
+            layers = { cmd = "echo '[1, 2]'", type = "toml" }
+        "#).unwrap();
+
+        assert_eq!(root["rev"].as_str(), Some("abc"));
+        assert_eq!(root["layers"].as_array().map(|x| x.len()), Some(2));
+
+        match resolve("x = { cmd = 'echo hi', glob = '*.none' }") {
+            Err(Error::InvalidTemplate(key, msg)) => assert!(key == "x" && msg.contains("`cmd` and `glob`"), "{}", msg),
+            x => panic!("expected invalid template, got {:?}", x),
+        }
+        assert!(matches!(resolve("x = { cmd = 'echo hi', '@env' = 'HOME' }"), Err(Error::InvalidTemplate(..))));
+
+        match resolve("fail = { cmd = 'echo oops >&2; exit 3', shell = 'sh' }") {
+            Err(Error::CommandFailed(_, msg)) => assert!(msg.contains("oops")),
+            x => panic!("expected failed command, got {:?}", x),
+        }
+
+        let res = resolve("slow = { cmd = 'sleep 5', shell = 'sh', timeout_secs = 0.1 }");
+        assert!(matches!(res, Err(Error::CommandFailed(..))));
+
+        // processes started in the background are stopped as well and do not keep the pipes open
+        let start = Instant::now();
+        let res = resolve("slow = { cmd = 'sleep 5 & wait', shell = 'sh', timeout_secs = 0.1 }");
+        assert!(matches!(res, Err(Error::CommandFailed(..))));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        assert!(matches!(resolve("x = { cmd = 'echo 1', type = 'int' }"), Err(Error::InvalidTemplate(..))));
+    }
 }
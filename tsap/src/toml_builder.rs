@@ -5,25 +5,119 @@ use std::env;
 use std::mem;
 use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
+use std::rc::Rc;
 
 pub use serde;
 pub use toml;
 
 use toml::Value;
-use crate::{Result, Error, templates::Templates};
+use crate::{Result, Error, Unapplied, templates::{self, Templates, TemplateKeys, Context, Policy}, interpolate::{interpolate, select}};
+use crate::provenance::{Changes, Origin, Source};
+use crate::run_dir::RunDir;
+use crate::lock::{self, Lock, Mismatch};
+use crate::plan::{self as plan_fmt, Plan, Step};
+use crate::strategy::{Strategy, Coercion, Rules};
+
+/// Default number of rounds after which template expansion is aborted
+pub const DEFAULT_MAX_ROUNDS: usize = 64;
+
+/// Parse a path segment as index into an array of length `len`
+///
+/// Negative indices count from the end, `-1` is the last element.
+fn index(segment: &str, len: usize) -> Option<usize> {
+    match segment.parse::<i64>().ok()? {
+        x if x < 0 => len.checked_sub(x.unsigned_abs() as usize),
+        x => Some(x as usize).filter(|x| *x < len),
+    }
+}
+
+/// Result of following a path as far as possible
+enum Lookup<'a> {
+    Found(&'a Value),
+    /// the segment at the index is not a key of the table, which has the listed keys
+    Missing(usize, Vec<&'a str>),
+    /// the segment at the first index is not an index into an array of the given length
+    OutOfRange(usize, usize),
+    /// the value before the segment at the index is neither a table nor an array
+    NotContainer(usize, &'a Value),
+}
+
+fn lookup<'a>(root: &'a Value, segments: &[String]) -> Lookup<'a> {
+    let mut node = root;
+
+    for (i, segment) in segments.iter().enumerate() {
+        node = match node {
+            Value::Table(t) => match t.get(segment) {
+                Some(x) => x,
+                None => return Lookup::Missing(i, t.keys().map(|x| x.as_str()).collect()),
+            },
+            Value::Array(a) => match index(segment, a.len()) {
+                Some(idx) => &a[idx],
+                None => return Lookup::OutOfRange(i, a.len()),
+            },
+            x => return Lookup::NotContainer(i, x),
+        };
+    }
+
+    Lookup::Found(node)
+}
+
+/// Name of key `k` in table `t`, keys escaped with `@@` are addressed by their unescaped name
+fn escaped(t: &toml::value::Table, k: String) -> String {
+    match t.contains_key(&k) {
+        false if t.contains_key(&format!("@@{}", k)) => format!("@@{}", k),
+        _ => k,
+    }
+}
+
+/// Append `val` to an array, the elements of `val` if it is an array itself
+fn append(a: &mut Vec<Value>, val: Value) {
+    match val {
+        Value::Array(elms) => a.extend(elms),
+        val => a.push(val),
+    }
+}
+
+/// Merge `action` into `root` and return the parts which could not be applied
+///
+/// `strategies` are the strategies registered for paths, a merge action adds
+/// its own strategy. Written values are recorded in `changes`. New keys in a
+/// table which contains a template wait until it is expanded, the template
+/// replaces the table. Fails for type mismatches in strict mode.
+fn merge(root: &mut Value, action: Action, strategies: &[(Path, Strategy)], coercion: Coercion, templates: &Templates, changes: &mut Changes) -> Result<Vec<Action>> {
+    let (action, strategy) = match action {
+        Action::Merge(path, val, strategy) => (Action::Set(path, val), Some(strategy)),
+        action => (action, None),
+    };
+    let names = templates.names();
+    let rules = Rules { paths: strategies, merge: strategy.as_ref(), coercion, templates: &names, keys: templates.keys };
 
-fn merge(mut root: Value, action: Action) -> (Value, Vec<Action>) {
+    let deferred = merge_at(root, action, &rules, changes)?;
+
+    // deferred parts of a merge keep its strategy
+    let deferred = deferred.into_iter()
+        .map(|action| match (action, &strategy) {
+            (Action::Set(path, val), Some(strategy)) => Action::Merge(path, val, strategy.clone()),
+            (action, _) => action,
+        })
+        .collect();
+
+    Ok(deferred)
+}
+
+fn merge_at(root: &mut Value, action: Action, rules: &Rules, changes: &mut Changes) -> Result<Vec<Action>> {
     // first iterate through root until we are after our path base
-    let mut local = &mut root;
+    let mut local = root;
     let paths = action.path().0.clone();
 
     let deferred = if paths.len() == 0 {
         match action {
             Action::Delete(_) => { Vec::new() },
             Action::Set(_, val) => 
-                merge_use_second(local, val, Mode::Set),
+                merge_use_second(local, val, Mode::Set, rules, &paths, changes)?,
             Action::Modify(_, val) => 
-                merge_use_second(local, val, Mode::Modify),
+                merge_use_second(local, val, Mode::Modify, rules, &paths, changes)?,
+            action => return Ok(vec![action]),
         }
     } else {
         let num = paths.len() - 1;
@@ -32,56 +126,105 @@ fn merge(mut root: Value, action: Action) -> (Value, Vec<Action>) {
             match local {
                 Value::Table(ref mut t) if t.contains_key(path) =>
                     local = t.get_mut(path).unwrap(),
-                _ => return (root, vec![action])
+                Value::Array(ref mut a) if index(path, a.len()).is_some() => {
+                    let idx = index(path, a.len()).unwrap();
+                    local = &mut a[idx]
+                },
+                _ => return Ok(vec![action])
             };
         }
 
-        let local = match local {
-            Value::Table(ref mut t) if t.contains_key(&paths[num]) => t,
-            _ => return (root, vec![action])
-        };
-
+        let last = &paths[num];
 
         // now that we are at the base, use recursive merging
-        match action {
-            Action::Delete(_) => { local.remove(&paths[num]).unwrap(); Vec::new() },
-            Action::Set(_, val) => 
-                merge_use_second(local.get_mut(&paths[num]).unwrap(), val, Mode::Set),
-            Action::Modify(_, val) => 
-                merge_use_second(local.get_mut(&paths[num]).unwrap(), val, Mode::Modify),
+        match (local, action) {
+            (Value::Table(ref mut t), Action::Delete(_)) if t.contains_key(last) => {
+                t.remove(last);
+                Vec::new()
+            },
+            (Value::Array(ref mut a), Action::Delete(_)) | (Value::Array(ref mut a), Action::Remove(_)) if index(last, a.len()).is_some() => {
+                let idx = index(last, a.len()).unwrap();
+                a.remove(idx);
+                changes.shift(&paths[..num], idx, -1);
+                Vec::new()
+            },
+            (Value::Table(ref mut t), Action::Add(_, val)) | (Value::Table(ref mut t), Action::Set(_, val)) if !t.contains_key(last) && !rules.has_template(t) => {
+                changes.write(&paths, &val);
+                t.insert(last.clone(), val);
+                Vec::new()
+            },
+            (Value::Array(ref mut a), Action::Insert(_, val)) if last.parse::<usize>().is_ok_and(|x| x <= a.len()) => {
+                let idx = last.parse().unwrap();
+                changes.shift(&paths[..num], idx, 1);
+                changes.write(&paths, &val);
+                a.insert(idx, val);
+                Vec::new()
+            },
+            (Value::Table(ref mut t), Action::Append(_, val)) if matches!(t.get(last), Some(Value::Array(_))) => {
+                if let Some(Value::Array(a)) = t.get_mut(last) {
+                    let len = a.len();
+                    append(a, val);
+                    changes.elements(&paths, a, len);
+                }
+                Vec::new()
+            },
+            (Value::Array(ref mut a), Action::Append(_, val)) if matches!(index(last, a.len()).map(|x| &a[x]), Some(Value::Array(_))) => {
+                let idx = index(last, a.len()).unwrap();
+                if let Value::Array(a) = &mut a[idx] {
+                    let len = a.len();
+                    append(a, val);
+                    changes.elements(&paths, a, len);
+                }
+                Vec::new()
+            },
+            (Value::Table(ref mut t), Action::Set(_, val)) if t.contains_key(last) =>
+                merge_use_second(t.get_mut(last).unwrap(), val, Mode::Set, rules, &paths, changes)?,
+            (Value::Table(ref mut t), Action::Modify(_, val)) if t.contains_key(last) =>
+                merge_use_second(t.get_mut(last).unwrap(), val, Mode::Modify, rules, &paths, changes)?,
+            (Value::Array(ref mut a), Action::Set(_, val)) if index(last, a.len()).is_some() => {
+                let idx = index(last, a.len()).unwrap();
+                merge_use_second(&mut a[idx], val, Mode::Set, rules, &paths, changes)?
+            },
+            (Value::Array(ref mut a), Action::Modify(_, val)) if index(last, a.len()).is_some() => {
+                let idx = index(last, a.len()).unwrap();
+                merge_use_second(&mut a[idx], val, Mode::Modify, rules, &paths, changes)?
+            },
+            (_, action) => return Ok(vec![action]),
         }
     };
 
     // add base path again if something is deferred
-    let deferred = deferred.into_iter().map(|mut p| {
+    Ok(deferred.into_iter().map(|mut p| {
         let mut new = paths.clone();
         new.extend(p.path().0.clone().into_iter().rev());
 
         p.mut_path().0 = new;
 
         p
-    }).collect();
-
-
-    (root, deferred)
+    }).collect())
 }
 
-fn merge_use_second(a: &mut Value, b: Value, mode: Mode) -> Vec<Action> {
+/// Merge `b` into `a`, which is at `key`
+///
+/// Paths of the returned actions are relative to `key` and reversed.
+fn merge_use_second(a: &mut Value, b: Value, mode: Mode, rules: &Rules, key: &[String], changes: &mut Changes) -> Result<Vec<Action>> {
+    let strategy = rules.at(key);
+
     match (a,b) {
-        (Value::Float(ref mut a), Value::Float(b)) => { *a = b; vec![] },
-        (Value::Integer(ref mut a), Value::Integer(b)) => { *a = b; vec![] },
-        (Value::String(ref mut a), Value::String(b)) => { *a = b; vec![] },
-        (Value::Boolean(ref mut a), Value::Boolean(b)) => { *a = b; vec![] },
-        (Value::Datetime(ref mut a), Value::Datetime(b)) => { *a = b; vec![] },
-        (Value::Array(ref mut a), Value::Array(b)) => { *a = b; vec![] },
-        (Value::Table(ref mut t1), Value::Table(t2)) => {
+        (Value::Array(ref mut a), Value::Array(b)) if strategy != Some(&Strategy::Replace) =>
+            merge_arrays(a, b, mode, rules, key, changes),
+        (Value::Table(ref mut t1), Value::Table(t2)) if strategy != Some(&Strategy::Replace) => {
             let mut deferred = Vec::new();
 
             // iterate through both tables
             // in set mode, we overwrite the entry, in modify mode we deferr until expansion
             for (k,v) in t2 {
+                let k = escaped(t1, k);
                 if let Some(ref mut a) = t1.get_mut(&k) {
-                    let res = merge_use_second(a, v, mode)
+                    let mut child = key.to_vec();
+                    child.push(k.clone());
+
+                    let res = merge_use_second(a, v, mode, rules, &child, changes)?
                         .into_iter().map(|mut p| {
                             p.mut_path().0.push(k.clone().into());
 
@@ -90,49 +233,339 @@ fn merge_use_second(a: &mut Value, b: Value, mode: Mode) -> Vec<Action> {
 
                     deferred.extend(res);
                 } else {
-                    if mode == Mode::Set {
+                    if mode == Mode::Set && rules.has_template(t1) {
+                        // the template replaces the table, wait until it is expanded
+                        deferred.push(Action::new(vec![k], v, mode));
+                    } else if mode == Mode::Set {
+                        let mut child = key.to_vec();
+                        child.push(k.clone());
+
+                        changes.write(&child, &v);
                         t1.insert(k, v);
                     } else {
-                        return vec![Action::new(vec![k], v, mode)];
+                        return Ok(vec![Action::new(vec![k], v, mode)]);
                     }
                 }
             }
 
-            deferred
+            Ok(deferred)
+        },
+        (a, b) if strategy == Some(&Strategy::Replace) || a.type_str() == b.type_str() => {
+            changes.write(key, &b);
+            *a = b;
+            Ok(vec![])
+        },
+        // references are converted to the type of `a` once they are interpolated
+        (a, Value::String(b)) if b.contains("${") && !a.is_table() => {
+            let b = Value::String(b);
+            changes.write(key, &b);
+            changes.reference(key, mem::replace(a, b));
+            Ok(vec![])
+        },
+        // convert to the type of `a` if possible, tables may be templates and wait for expansion
+        (a, b) => match rules.coercion.apply(a, b) {
+            Ok(b) => {
+                changes.write(key, &b);
+                *a = b;
+                Ok(vec![])
+            },
+            Err(b) if rules.coercion.is_strict() && !a.is_table() && !b.is_table() =>
+                Err(Error::TypeMismatch(key.join("."), a.type_str(), b.type_str())),
+            Err(b) => Ok(vec![Action::new(vec![], b, mode)]),
+        },
+    }
+}
+
+/// Merge array `b` into `a` with the strategy for `key`, by default `a` is replaced
+fn merge_arrays(a: &mut Vec<Value>, b: Vec<Value>, mode: Mode, rules: &Rules, key: &[String], changes: &mut Changes) -> Result<Vec<Action>> {
+    // merge element `b` into the element at `idx` of `a`
+    let mut deferred = Vec::new();
+    let mut merge_elm = |a: &mut Vec<Value>, idx: usize, b: Value, changes: &mut Changes| -> Result<()> {
+        let mut child = key.to_vec();
+        child.push(idx.to_string());
+
+        let res = merge_use_second(&mut a[idx], b, mode, rules, &child, changes)?
+            .into_iter().map(|mut p| {
+                p.mut_path().0.push(idx.to_string());
+
+                p
+            });
+
+        deferred.extend(res);
+
+        Ok(())
+    };
+
+    // existing elements keep their origins, only added or merged ones are recorded
+    let len = a.len();
+    match rules.at(key) {
+        Some(Strategy::Append) => {
+            a.extend(b);
+            changes.elements(key, a, len);
+        },
+        Some(Strategy::Prepend) => {
+            let num = b.len();
+            a.splice(0..0, b);
+            changes.shift(key, 0, num as isize);
+            changes.elements(key, &a[..num], 0);
+        },
+        Some(Strategy::Union) => {
+            for elm in b {
+                if !a.contains(&elm) {
+                    a.push(elm);
+                }
+            }
+            changes.elements(key, a, len);
+        },
+        Some(Strategy::Deep) => for (idx, elm) in b.into_iter().enumerate() {
+            match idx < a.len() {
+                true => merge_elm(a, idx, elm, changes)?,
+                false => {
+                    a.push(elm);
+                    changes.elements(key, a, idx);
+                },
+            }
+        },
+        Some(Strategy::MergeBy(field)) => for elm in b {
+            let pos = elm.get(field.as_str())
+                .and_then(|id| a.iter().position(|x| x.get(field.as_str()) == Some(id)));
+
+            match pos {
+                Some(idx) => merge_elm(a, idx, elm, changes)?,
+                None => {
+                    a.push(elm);
+                    changes.elements(key, a, a.len() - 1);
+                },
+            }
+        },
+        Some(Strategy::Replace) | None => {
+            *a = b;
+            match a.is_empty() {
+                true => changes.write(key, &Value::Array(Vec::new())),
+                false => changes.elements(key, a, 0),
+            }
         },
-        (_, b) => vec![Action::new(vec![], b, mode)],
     }
+
+    Ok(deferred)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Path(Vec<String>);
 
 impl FromStr for Path {
     type Err = Error;
 
     fn from_str(path: &str) -> Result<Path> {
-        if path.is_empty() {
-            return Ok(Path(Vec::new()));
-        }
-
-        let parsed_path = path.split('.').map(|x| x.to_string())
-            .collect::<Vec<_>>();
-
-        Ok(Path(parsed_path))
+        split_path(path)
+            .map(Path)
+            .ok_or_else(|| Error::InvalidPath(path.to_string()))
     }
 }
 
 impl From<&str> for Path {
     fn from(path: &str) -> Path {
-        if path.is_empty() {
-            return Path(Vec::new());
+        let parsed_path = split_path(path)
+            .unwrap_or_else(|| path.split('.').map(|x| x.to_string()).collect());
+
+        Path(parsed_path)
+    }
+}
+
+/// Split a dotted key into its segments
+///
+/// Segments can be quoted to contain dots, like `model."layer.1".units`, and
+/// followed by array indices, like `layers[2].units` or `layers[-1]`.
+/// Returns `None` for unterminated quotes, empty segments or invalid indices.
+fn split_path(path: &str) -> Option<Vec<String>> {
+    let path = path.trim();
+    if path.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let mut segments = Vec::new();
+    let mut rest = path;
+    loop {
+        let segment = rest.trim_start();
+        let (segment, remaining) = match segment.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => {
+                let end = segment[1..].find(quote)? + 1;
+                (segment[1..end].to_string(), &segment[end + 1..])
+            },
+            _ => {
+                let end = segment.find(['.', '[']).unwrap_or(segment.len());
+                let bare = segment[..end].trim();
+                if bare.is_empty() {
+                    return None;
+                }
+
+                (bare.to_string(), &segment[end..])
+            },
+        };
+        segments.push(segment);
+
+        // array indices like `layers[2][-1]`
+        let mut remaining = remaining.trim_start();
+        while let Some(rest) = remaining.strip_prefix('[') {
+            let end = rest.find(']')?;
+            let idx = rest[..end].trim().parse::<i64>().ok()?;
+            segments.push(idx.to_string());
+            remaining = rest[end + 1..].trim_start();
         }
 
-        let parsed_path = path.split('.').map(|x| x.to_string())
-            .collect::<Vec<_>>();
+        match remaining.strip_prefix('.') {
+            Some(remaining) => rest = remaining,
+            None if remaining.is_empty() => return Some(segments),
+            None => return None,
+        }
+    }
+}
 
-        Path(parsed_path)
+/// Replace `@file` arguments by the lines of the file, recursively
+///
+/// `files` is the chain of files currently expanded. Everything after `--`
+/// goes to `passthrough`.
+fn expand_args(
+    args: Vec<(Origin, String)>,
+    files: &mut Vec<path::PathBuf>,
+    out: &mut Vec<(Origin, String)>,
+    passthrough: &mut Option<Vec<String>>,
+) -> Result<()> {
+    for (origin, arg) in args {
+        if let Some(rest) = passthrough {
+            rest.push(arg);
+            continue;
+        }
+
+        if arg == "--" {
+            *passthrough = Some(Vec::new());
+            continue;
+        }
+
+        let file = match arg.strip_prefix('@') {
+            Some(file) if !arg.contains('=') => path::PathBuf::from(file),
+            _ => { out.push((origin, arg)); continue },
+        };
+
+        if files.contains(&file) {
+            let chain = files.iter().chain(Some(&file))
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>();
+
+            return Err(Error::IncludeCycle(chain.join(" -> ")));
+        }
 
+        let content = std::fs::read_to_string(&file)?;
+        let lines = content.lines()
+            .enumerate()
+            .map(|(i, x)| (i, x.trim()))
+            .filter(|(_, x)| !x.is_empty() && !x.starts_with('#'))
+            .map(|(i, x)| (Origin::File { path: file.clone(), line: Some(i + 1) }, x.to_string()))
+            .collect();
+
+        files.push(file);
+        expand_args(lines, files, out, passthrough)?;
+        files.pop();
+    }
+
+    Ok(())
+}
+
+/// Split a command line argument `key=value` into key and value
+///
+/// The value is parsed as TOML if possible and taken as string otherwise, so
+/// `name=gru` and `name="gru"` are the same.
+fn parse_arg(arg: &str) -> Result<(&str, Value)> {
+    // the key ends at the first `=` outside of quotes
+    let mut quote = None;
+    let eq = arg.char_indices()
+        .find(|(_, c)| match (quote, *c) {
+            (None, '"') | (None, '\'') => { quote = Some(*c); false },
+            (Some(q), c) if q == c => { quote = None; false },
+            (None, '=') => true,
+            _ => false,
+        })
+        .map(|(idx, _)| idx)
+        .ok_or_else(|| Error::InvalidArg(arg.to_string()))?;
+
+    let raw = arg[eq + 1..].trim();
+    let value = templates::parse_value(raw).unwrap_or_else(|_| Value::String(raw.to_string()));
+
+    Ok((arg[..eq].trim(), value))
+}
+
+/// Parse an argument `key=strategy` of `--merge`
+fn parse_strategy(arg: &str) -> Result<(Path, Strategy)> {
+    let (key, value) = parse_arg(arg)?;
+    let strategy = match value {
+        Value::String(x) => x.parse()?,
+        x => x.to_string().parse()?,
+    };
+
+    Ok((Path::from_str(key)?, strategy))
+}
+
+/// Parse the key of argument `arg`, which must not be empty
+fn parse_key(key: &str, arg: &str) -> Result<Path> {
+    let path = Path::from_str(key)?;
+    if path.0.is_empty() {
+        return Err(Error::InvalidArg(arg.to_string()));
+    }
+
+    Ok(path)
+}
+
+impl Path {
+    /// Look up the value a path points to, numeric segments index into arrays
+    pub fn get<'a>(&self, root: &'a Value) -> Option<&'a Value> {
+        self.0.iter().try_fold(root, |node, key| match node {
+            Value::Array(a) => index(key, a.len()).map(|x| &a[x]),
+            _ => node.get(key.as_str()),
+        })
+    }
+
+    pub(crate) fn get_mut<'a>(&self, root: &'a mut Value) -> Option<&'a mut Value> {
+        self.0.iter().try_fold(root, |node, key| match node {
+            Value::Array(a) => index(key, a.len()).map(move |x| &mut a[x]),
+            _ => node.get_mut(key.as_str()),
+        })
+    }
+}
+
+impl Path {
+    /// Replace negative indices by the position they currently point to
+    ///
+    /// Keys which only exist escaped with `@@` are replaced by the escaped key as well.
+    pub(crate) fn absolute(&self, root: &Value) -> Path {
+        let mut node = Some(root);
+        let segments = self.0.iter()
+            .map(|key| match node {
+                Some(Value::Array(a)) => {
+                    let idx = index(key, a.len());
+                    node = idx.map(|x| &a[x]);
+                    match idx {
+                        Some(idx) if key.starts_with('-') => idx.to_string(),
+                        _ => key.clone(),
+                    }
+                },
+                Some(Value::Table(t)) => {
+                    let key = escaped(t, key.clone());
+                    node = t.get(&key);
+                    key
+                },
+                Some(_) => {
+                    node = None;
+                    key.clone()
+                },
+                None => key.clone(),
+            })
+            .collect();
+
+        Path(segments)
+    }
+
+    pub(crate) fn segments(&self) -> &[String] {
+        &self.0
     }
 }
 
@@ -142,11 +575,23 @@ impl std::string::ToString for Path {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Action {
+    /// Change an existing value
     Modify(Path, Value),
+    /// Set a value, whether it exists or not
     Set(Path, Value),
+    /// Add a value which does not exist yet
+    Add(Path, Value),
     Delete(Path),
+    /// Append to an array, all elements if the value is an array
+    Append(Path, Value),
+    /// Insert into an array before the element at the path
+    Insert(Path, Value),
+    /// Remove the array element at the path
+    Remove(Path),
+    /// Set a value, merged with a strategy
+    Merge(Path, Value, Strategy),
 }
 
 impl Action {
@@ -154,7 +599,12 @@ impl Action {
         match self {
             Action::Modify(p, _) => p,
             Action::Set(p, _) => p,
+            Action::Add(p, _) => p,
             Action::Delete(p) => p,
+            Action::Append(p, _) => p,
+            Action::Insert(p, _) => p,
+            Action::Remove(p) => p,
+            Action::Merge(p, ..) => p,
         }
     }
 
@@ -162,7 +612,20 @@ impl Action {
         match self {
             Action::Modify(p, _) => p,
             Action::Set(p, _) => p,
+            Action::Add(p, _) => p,
             Action::Delete(p) => p,
+            Action::Append(p, _) => p,
+            Action::Insert(p, _) => p,
+            Action::Remove(p) => p,
+            Action::Merge(p, ..) => p,
+        }
+    }
+
+    /// Set a value, merged with `strategy` if given
+    fn merge(path: Path, val: Value, strategy: Option<Strategy>) -> Self {
+        match strategy {
+            Some(strategy) => Action::Merge(path, val, strategy),
+            None => Action::Set(path, val),
         }
     }
 
@@ -173,6 +636,28 @@ impl Action {
             Mode::Delete => Action::Delete(p),
             Mode::Modify => Action::Modify(p, val),
             Mode::Set => Action::Set(p, val),
+            Mode::Add => Action::Add(p, val),
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (mode, path, val) = match self {
+            Action::Modify(p, v) => ("modify".into(), p, Some(v)),
+            Action::Set(p, v) => ("set".into(), p, Some(v)),
+            Action::Add(p, v) => ("add".into(), p, Some(v)),
+            Action::Delete(p) => ("delete".into(), p, None),
+            Action::Append(p, v) => ("append".into(), p, Some(v)),
+            Action::Insert(p, v) => ("insert".into(), p, Some(v)),
+            Action::Remove(p) => ("remove".into(), p, None),
+            Action::Merge(p, v, strategy) => (format!("merge ({})", strategy), p, Some(v)),
+        };
+
+        match (path.0.is_empty(), val) {
+            (true, Some(val)) => write!(f, "{} {}", mode, plan_fmt::inline(val)),
+            (false, Some(val)) => write!(f, "{} {} = {}", mode, path.to_string(), plan_fmt::inline(val)),
+            (_, None) => write!(f, "{} {}", mode, path.to_string()),
         }
     }
 }
@@ -181,13 +666,31 @@ impl Action {
 pub enum Mode {
     Modify,
     Set,
+    Add,
     Delete,
 }
 
 pub struct TomlBuilder {
     pub root: toml::Value,
     templates: Templates,
-    actions: Vec<Action>,
+    context: Context,
+    actions: Vec<(Action, Rc<Source>)>,
+    max_rounds: usize,
+    explain: Option<String>,
+    plan_only: bool,
+    /// `apply` succeeded
+    applied: bool,
+    run_dir: Option<RunDir>,
+    run_path: Option<path::PathBuf>,
+    lock_file: Option<path::PathBuf>,
+    /// merge strategies for paths
+    strategies: Vec<(Path, Strategy)>,
+    coercion: Coercion,
+    /// references which replaced a value of another type, with that value
+    references: Vec<(Path, Value)>,
+    /// command line arguments, saved in the run directory
+    args: Vec<String>,
+    passthrough: Vec<String>,
 }
 
 impl Default for TomlBuilder {
@@ -195,7 +698,20 @@ impl Default for TomlBuilder {
         TomlBuilder {
             root: toml::Value::Integer(0),
             templates: Templates::default(),
-            actions: Vec::new()
+            context: Context::default(),
+            actions: Vec::new(),
+            max_rounds: DEFAULT_MAX_ROUNDS,
+            explain: None,
+            plan_only: false,
+            applied: false,
+            run_dir: None,
+            run_path: None,
+            lock_file: None,
+            strategies: Vec::new(),
+            coercion: Coercion::default(),
+            references: Vec::new(),
+            args: Vec::new(),
+            passthrough: Vec::new(),
         }
     }
 }
@@ -204,11 +720,11 @@ impl TryFrom<toml::Value> for TomlBuilder {
     type Error = Error;
 
     fn try_from(root: toml::Value) -> Result<TomlBuilder> {
-        let builder = TomlBuilder {
-            templates: Templates::default(),
-            actions: Vec::new(),
+        let mut builder = TomlBuilder {
             root,
+            ..TomlBuilder::default()
         };
+        builder.context.provenance.record("", &builder.root, &Source::new(Origin::Amend, String::new()));
 
         Ok(builder)
     }
@@ -234,115 +750,604 @@ impl TryFrom<String> for TomlBuilder {
 
 impl TomlBuilder {
     pub fn from_file<T: AsRef<path::Path>>(path: T) -> Result<TomlBuilder> {
-        let mut f = File::open(path)?;
+        let mut f = File::open(path.as_ref())?;
 
         let mut content = String::new();
         f.read_to_string(&mut content)?;
-        
-        content.try_into()
+
+        let mut builder = TomlBuilder {
+            root: content.parse()?,
+            ..TomlBuilder::default()
+        };
+        builder.context.include(String::new(), path.as_ref().to_path_buf())?;
+
+        let source = Source::file(path.as_ref().to_path_buf(), String::new(), &content);
+        builder.context.provenance.record("", &builder.root, &source);
+
+        Ok(builder)
+    }
+
+    /// Restrict which commands and files templates may access
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.context.policy = policy;
+
+        self
     }
 
-    pub fn amend_args(mut self) -> Result<Self> {
-        let mut mode = Mode::Modify;
-            
-        for arg in env::args().skip(1) {
+    /// Set which keys are expanded as templates
+    ///
+    /// Use [`TemplateKeys::Prefixed`] if the configuration has ordinary keys
+    /// named like a template, for example a `cmd` field.
+    pub fn template_keys(mut self, keys: TemplateKeys) -> Self {
+        self.templates.keys(keys);
+
+        self
+    }
+
+    /// Seed the run identifier of `run_id` templates to make it reproducible
+    pub fn run_seed(mut self, seed: u64) -> Self {
+        self.context.seed = Some(seed);
+
+        self
+    }
+
+    /// Limit the number of alternating override and template expansion rounds
+    ///
+    /// Templates producing further templates (for example a command printing
+    /// an inline table) are expanded again in the next round, this bounds the
+    /// number of rounds before `apply` gives up.
+    pub fn max_rounds(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = max_rounds;
+
+        self
+    }
+
+    /// Create an output directory for the run after `apply`
+    ///
+    /// The directory receives the resolved configuration, the command line
+    /// arguments and the outputs of all templates, see [`RunDir`].
+    pub fn run_dir(mut self, run_dir: RunDir) -> Self {
+        self.run_dir = Some(run_dir);
+
+        self
+    }
+
+    /// Path of the output directory created by `apply`
+    pub fn run_path(&self) -> Option<&path::Path> {
+        self.run_path.as_deref()
+    }
+
+    /// Write the output of every template expansion to a lock file after `apply`
+    pub fn lock_file<T: AsRef<path::Path>>(mut self, path: T) -> Self {
+        self.lock_file = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Merge values at or below `path` with `strategy`
+    ///
+    /// Applies to all merges, from `amend`, `amend_file` and the command
+    /// line. The strategy of the longest matching path wins, an empty path
+    /// matches everything.
+    pub fn merge_strategy<P: Into<Path>>(mut self, path: P, strategy: Strategy) -> Self {
+        self.strategies.push((path.into(), strategy));
+
+        self
+    }
+
+    /// Convert values which override a value of another type, see [`Coercion`]
+    pub fn coercion(mut self, coercion: Coercion) -> Self {
+        self.coercion = coercion;
+
+        self
+    }
+
+    /// Use the template outputs recorded in a lock file instead of expanding templates
+    ///
+    /// Templates are not evaluated, unless enabled with [`Self::verify_replay`].
+    /// A template without recorded output fails the expansion.
+    pub fn replay<T: AsRef<path::Path>>(mut self, path: T) -> Result<Self> {
+        self.context.replay = Some(Lock::read(path.as_ref())?);
+
+        Ok(self)
+    }
+
+    /// Evaluate templates while replaying and compare them to the recorded output
+    ///
+    /// Differences are listed by [`Self::mismatches`], `now` and `run_id`
+    /// are not compared. Templates run live, combine with [`Policy::safe`] to
+    /// not run any commands.
+    pub fn verify_replay(mut self) -> Self {
+        self.context.verify_replay = true;
+
+        self
+    }
+
+    /// Templates of the last run whose live result differs from the recorded one
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.context.mismatches
+    }
+
+    /// Apply overrides from the command line arguments of the process
+    pub fn amend_args(self) -> Result<Self> {
+        self.amend_args_from(env::args().skip(1))
+    }
+
+    /// Apply overrides from a list of arguments, without the program name
+    ///
+    /// `key=value` changes an existing value, `+key=value` adds a new one,
+    /// `++key=value` sets a value whether it exists or not and `~key` deletes
+    /// a key. Array elements are addressed as `layers[2]` or `layers[-1]`,
+    /// `key+=value` appends to an array, `+layers[1]=value` inserts before
+    /// the element and `~layers[1]` removes it. Overrides which can not be
+    /// applied, for example changing a key which does not exist, fail `apply`.
+    ///
+    /// An argument `@file` (without `=`) is replaced by the lines of `file`,
+    /// empty lines and lines starting with `#` are skipped. Parsing stops at
+    /// `--`, the remaining arguments are available with [`passthrough`](Self::passthrough).
+    pub fn amend_args_from<I, T>(mut self, args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        let args = args.into_iter()
+            .enumerate()
+            .map(|(i, arg)| {
+                let arg = arg.as_ref().to_string();
+                (Origin::Arg { index: i + 1, arg: arg.clone() }, arg)
+            })
+            .collect();
+
+        let mut expanded = Vec::new();
+        let mut passthrough = None;
+        expand_args(args, &mut Vec::new(), &mut expanded, &mut passthrough)?;
+        self.passthrough = passthrough.unwrap_or_default();
+
+        let mut args = expanded.into_iter();
+
+        while let Some((origin, arg)) = args.next() {
+            self.args.push(arg.clone());
+
             match arg.as_str() {
-                "-a" => { mode = Mode::Set; continue },
-                "-m" => { mode = Mode::Modify; continue },
-                "-d" => { mode = Mode::Delete; continue },
+                "--plan" => { self.plan_only = true; continue },
+                "--explain" => {
+                    self.explain = args.next().map(|x| x.1);
+                    self.args.extend(self.explain.clone());
+                    continue
+                },
+                "--merge" => {
+                    let (_, arg) = args.next().ok_or_else(|| Error::InvalidArg(arg.clone()))?;
+                    self.args.push(arg.clone());
+                    self.strategies.push(parse_strategy(&arg)?);
+                    continue
+                },
                 _ => {}
             }
 
-            let elm = if mode != Mode::Delete {
-                //if arg.matches('=').count() != 1 {
-                //    return Err(Error::InvalidArg(arg));
-                //}
-
-                //let elms = arg.splitn(2, '=').into_iter().collect::<Vec<_>>();
-                //let path = Path::from_str(&elms[0]).unwrap();
-                let value = toml::from_str(&arg)?;
-                let path = Path(Vec::new());
-                
-                //let value = if let Ok(val) = elms[1].parse::<i64>() {
-                //    Value::Integer(val)
-                //} else {
-                //    Value::String(elms[1].to_string())
-                //};
-
-                match mode {
-                    Mode::Modify => Action::Modify(path, value),
-                    Mode::Set => Action::Set(path, value),
-                    _ => unreachable!()
+            if let Some(key) = arg.strip_prefix("--explain=") {
+                self.explain = Some(key.to_string());
+                continue;
+            }
+
+            if let Some(arg) = arg.strip_prefix("--merge=") {
+                self.strategies.push(parse_strategy(arg)?);
+                continue;
+            }
+
+            // a trailing index in brackets addresses an array element
+            let elm = if let Some(arg) = arg.strip_prefix("++") {
+                let (key, value) = parse_arg(arg)?;
+                Action::Set(parse_key(key, arg)?, value)
+            } else if let Some(arg) = arg.strip_prefix('+') {
+                let (key, value) = parse_arg(arg)?;
+                match key.ends_with(']') {
+                    true => Action::Insert(parse_key(key, arg)?, value),
+                    false => Action::Add(parse_key(key, arg)?, value),
+                }
+            } else if let Some(key) = arg.strip_prefix('~') {
+                match key.trim_end().ends_with(']') {
+                    true => Action::Remove(parse_key(key, &arg)?),
+                    false => Action::Delete(parse_key(key, &arg)?),
                 }
             } else {
-                let path = Path::from_str(&arg).unwrap();
-                Action::Delete(path)
+                let (key, value) = parse_arg(&arg)?;
+                match key.strip_suffix('+') {
+                    Some(key) => Action::Append(parse_key(key, &arg)?, value),
+                    None => Action::Modify(parse_key(key, &arg)?, value),
+                }
             };
 
-            self.actions.push(elm);
+            let source = Source::new(origin, elm.path().to_string());
+            self.actions.push((elm, Rc::new(source)));
         }
 
         Ok(self)
     }
 
-    pub fn resolve_templates(&mut self) -> bool {
-        let (root, any_changed) = self.templates.resolve(mem::replace(&mut self.root, Value::Integer(0)));
+    /// Error for the overrides which could not be applied
+    ///
+    /// A single override fails with `InvalidOverride`, several with `MergeFailed` listing each of them.
+    fn unapplied(&self) -> Error {
+        let mut unapplied = self.actions.iter()
+            .map(|(action, source)| self.diagnose(action, source))
+            .collect::<Vec<_>>();
+
+        match unapplied.len() {
+            1 => {
+                let unapplied = unapplied.remove(0);
+                Error::InvalidOverride(format!("{} ({})", unapplied.action, unapplied.origin), unapplied.details())
+            },
+            _ => Error::MergeFailed(unapplied),
+        }
+    }
+
+    /// Find out why `action` could not be applied
+    fn diagnose(&self, action: &Action, source: &Source) -> Unapplied {
+        let segments = &action.path().0;
+        let key = action.path().to_string();
+        let prefix = |i: usize| Path(segments[..i].to_vec()).to_string();
+
+        let value = match action {
+            Action::Modify(_, val) | Action::Set(_, val) | Action::Add(_, val) | Action::Append(_, val) |
+            Action::Insert(_, val) | Action::Merge(_, val, _) => Some(val),
+            Action::Delete(_) | Action::Remove(_) => None,
+        };
+
+        let mut existing = None;
+        let mut suggestion = None;
+        let reason = match (action, lookup(&self.root, segments)) {
+            // elements are inserted into and removed from the parent
+            (Action::Insert(..), _) | (Action::Remove(_), _) => {
+                let parent = prefix(segments.len().saturating_sub(1));
+
+                match lookup(&self.root, &segments[..segments.len().saturating_sub(1)]) {
+                    Lookup::Found(Value::Array(a)) => format!("index out of range, `{}` has {} elements", parent, a.len()),
+                    Lookup::Found(found) => {
+                        existing = Some((found.type_str().to_string(), plan_fmt::inline(found)));
+                        format!("`{}` is not an array", parent)
+                    },
+                    _ => format!("`{}` does not exist", parent),
+                }
+            },
+            (_, Lookup::Found(found)) => {
+                existing = Some((found.type_str().to_string(), plan_fmt::inline(found)));
+
+                match (action, value) {
+                    (Action::Add(..), _) => format!("`{0}` already exists, use `++{0}=...` to override it", key),
+                    (Action::Append(..), _) => format!("`{}` is not an array", key),
+                    (_, Some(val)) => format!("can not override {} with {}", found.type_str(), val.type_str()),
+                    (_, None) => format!("`{}` can not be changed", key),
+                }
+            },
+            (_, Lookup::Missing(i, keys)) => {
+                suggestion = crate::suggest::closest(&segments[i], keys).map(|x| {
+                    let mut path = segments.clone();
+                    path[i] = x.to_string();
+
+                    Path(path).to_string()
+                });
+
+                match action {
+                    _ if i + 1 < segments.len() => format!("the parent of `{}` does not exist", key),
+                    Action::Modify(..) => format!("`{0}` does not exist, use `+{0}=...` to add it", key),
+                    _ => format!("`{}` does not exist", key),
+                }
+            },
+            (_, Lookup::OutOfRange(i, len)) =>
+                format!("`{}` does not exist, index out of range, `{}` has {} elements", key, prefix(i), len),
+            (_, Lookup::NotContainer(i, found)) =>
+                format!("the parent `{}` is {} {}, not a table", prefix(i), found.type_str(), plan_fmt::inline(found)),
+        };
+
+        Unapplied {
+            action: action.to_string(),
+            origin: source.origin(&key).to_string(),
+            path: key,
+            value: value.map(plan_fmt::inline),
+            existing,
+            reason,
+            suggestion,
+        }
+    }
+
+    /// Arguments after `--`, which are meant for the application
+    pub fn passthrough(&self) -> &[String] {
+        &self.passthrough
+    }
+
+    pub fn resolve_templates(&mut self) -> Result<bool> {
+        let root = mem::replace(&mut self.root, Value::Integer(0));
+        let (root, any_changed) = self.templates.resolve(&mut self.context, root)?;
         self.root = root;
 
-        any_changed
+        Ok(any_changed)
+    }
+
+    pub fn apply_actions(&mut self) -> Result<bool> {
+        self.apply_actions_planned(None)
     }
 
-    pub fn apply_actions(&mut self) -> bool {
+    fn apply_actions_planned(&mut self, mut plan: Option<&mut Plan>) -> Result<bool> {
         let mut deferred = Vec::new();
 
         // actions are applied in the same order as they came in
-        for action in self.actions.drain(..) {
-            let (root, mut tmp) = merge(mem::replace(&mut self.root, Value::Integer(0)), action);
-            self.root = root;
-            deferred.append(&mut tmp);
+        let mut actions = mem::take(&mut self.actions).into_iter();
+        while let Some((mut action, source)) = actions.next() {
+            let path = action.path().absolute(&self.root);
+            *action.mut_path() = path;
+
+            let text = action.to_string();
+            let origin = source.origin(&action.path().to_string());
+
+            // a strict merge can fail halfway, keep the value it merges into and the action
+            let snapshot = match self.coercion.is_strict() {
+                true => Some((action.path().get(&self.root).cloned(), action.clone())),
+                false => None,
+            };
+
+            let mut changes = Changes::default();
+            let tmp = match merge(&mut self.root, action, &self.strategies, self.coercion, &self.templates, &mut changes) {
+                Ok(tmp) => tmp,
+                Err(err) => {
+                    if let Some((value, action)) = snapshot {
+                        if let (Some(value), Some(target)) = (value, action.path().get_mut(&mut self.root)) {
+                            *target = value;
+                        }
+                        deferred.push((action, source));
+                    }
+
+                    // the failed and all remaining actions stay pending
+                    deferred.extend(actions);
+                    self.actions = deferred;
+
+                    return Err(err);
+                },
+            };
+
+            if let Some(plan) = plan.as_deref_mut() {
+                if tmp.len() != 1 || tmp[0].to_string() != text {
+                    plan.push(Step::Apply { action: text, origin: origin.clone() });
+                }
+
+                for action in &tmp {
+                    plan.push(Step::Defer { action: action.to_string(), origin: origin.clone() });
+                }
+            }
+
+            self.references.extend(changes.references.drain(..).map(|(key, val)| (Path(key), val)));
+
+            // record origins of the written values, deferred parts are recorded once applied
+            self.context.provenance.apply(changes, &source);
+
+            deferred.extend(tmp.into_iter().map(|x| (x, source.clone())));
         }
 
         self.actions.append(&mut deferred);
 
-        !self.actions.is_empty()
+        Ok(!self.actions.is_empty())
     }
 
+    /// Apply overrides, expand templates and write run directory and lock file
+    ///
+    /// With `--plan` nothing is applied, this fails with
+    /// [`Error::PlanRequested`] containing the [`plan`](Self::plan) instead.
     pub fn apply(&mut self) -> Result<()> {
+        if self.plan_only {
+            return Err(Error::PlanRequested(self.plan()?));
+        }
+
+        self.resolve(None)?;
+
+        if let Some(run_dir) = &self.run_dir {
+            let started = self.context.started();
+            let path = run_dir.create(&mut self.root, self.templates.keys, started)?;
+            let escaped = templates::escape(&self.root, &self.templates.names());
+            RunDir::save(&path, &escaped, &self.args, &self.context.outputs)?;
+
+            self.run_path = Some(path);
+        }
+
+        if let Some(path) = &self.lock_file {
+            lock::write(path, &self.context.outputs)?;
+        }
+
+        self.applied = true;
+
+        Ok(())
+    }
+
+    /// Whether `apply` succeeded and no overrides were added since
+    pub fn is_applied(&self) -> bool {
+        self.applied && self.actions.is_empty()
+    }
+
+    /// List the steps `apply` would take without changing the builder
+    ///
+    /// Templates are expanded to find further templates and targets of
+    /// overrides, but commands are not run and their output is a placeholder.
+    /// Nothing is written, neither run directories, lock files nor counters.
+    pub fn plan(&mut self) -> Result<Plan> {
+        let root = self.root.clone();
+        let actions = self.actions.clone();
+        let references = self.references.clone();
+        let context = self.context.clone();
+
+        self.context.dry_run = true;
+        let mut plan = Plan::default();
+        let res = self.resolve(Some(&mut plan));
+
+        self.root = root;
+        self.actions = actions;
+        self.references = references;
+        self.context = context;
+
+        res.map(|_| plan)
+    }
+
+    /// Apply overrides and expand templates until the configuration is final
+    fn resolve(&mut self, mut plan: Option<&mut Plan>) -> Result<()> {
         let mut any_resolved = true;
+        let mut rounds = 0;
+
+        // all templates of this run see the same time and run identifier, keys
+        // expanded by a previous run are no longer part of any include chain
+        self.context.start_run();
+        self.context.reset_includes();
+        self.context.mismatches.clear();
 
         // apply alternating actions and expand templates
         loop {
-            let deferred_actions = self.apply_actions();
+            let deferred_actions = self.apply_actions_planned(plan.as_deref_mut())?;
             if !any_resolved {
+                // select branches of conditionals once the values they depend on are final,
+                // the selected branches may contain further templates or targets of actions
+                let (root, selected) = select(&self.root, self.templates.keys)?;
+                self.root = root;
+
+                if selected {
+                    if let Some(plan) = plan.as_deref_mut() {
+                        plan.push(Step::Select);
+                    }
+
+                    any_resolved = true;
+                    continue;
+                }
+
                 if deferred_actions {
                     // we are stuck, there are pending actions but no templates expansion left
-                    return Err(Error::MergeFailed);
+                    return Err(self.unapplied());
                 } else {
-                    // done
+                    // done, resolve references now that all values are final
+                    self.root = interpolate(&self.root, self.templates.keys)?;
+                    self.convert_references()?;
+                    self.root = templates::unescape(mem::replace(&mut self.root, Value::Integer(0)));
+                    self.context.provenance.retain(&self.root);
+
                     return Ok(());
                 }
             }
 
-            any_resolved = self.resolve_templates()
+            let expanded = self.context.outputs.len();
+            any_resolved = self.resolve_templates()?;
+
+            if let Some(plan) = plan.as_deref_mut() {
+                for output in &self.context.outputs[expanded..] {
+                    plan.push(Step::Expand {
+                        key: output.key.clone(),
+                        template: output.name.clone(),
+                        args: Value::Table(output.args.clone()),
+                    });
+                }
+            }
+
+            rounds += 1;
+            if any_resolved && rounds > self.max_rounds {
+                return Err(Error::ResolutionLimit(self.max_rounds));
+            }
+        }
+    }
+
+    /// Convert interpolated references to the type of the value they replaced
+    fn convert_references(&mut self) -> Result<()> {
+        for (path, target) in mem::take(&mut self.references) {
+            let val = match path.get_mut(&mut self.root) {
+                Some(val) if val.type_str() != target.type_str() => val,
+                _ => continue,
+            };
+
+            *val = self.coercion.apply(&target, val.clone())
+                .map_err(|x| Error::TypeMismatch(path.to_string(), target.type_str(), x.type_str()))?;
         }
+
+        Ok(())
     }
 
     pub fn amend_file<T: AsRef<std::path::Path>>(self, path: T) -> Result<Self> {
-        let mut f = File::open(path)?;
+        self.amend_file_strategy(path, None)
+    }
+
+    /// Merge a file into the configuration with `strategy`
+    pub fn amend_file_with<T: AsRef<std::path::Path>>(self, path: T, strategy: Strategy) -> Result<Self> {
+        self.amend_file_strategy(path, Some(strategy))
+    }
+
+    fn amend_file_strategy<T: AsRef<std::path::Path>>(mut self, path: T, strategy: Option<Strategy>) -> Result<Self> {
+        let mut f = File::open(path.as_ref())?;
         let mut content = String::new();
         f.read_to_string(&mut content)?;
 
-        self.amend("", &content)
+        let root = toml::from_str(&content)?;
+        let source = Source::file(path.as_ref().to_path_buf(), String::new(), &content);
+        self.actions.push((Action::merge(Path(Vec::new()), root, strategy), Rc::new(source)));
+
+        Ok(self)
     }
 
-    pub fn amend<P: Into<Path>, T: AsRef<str>>(mut self, path: P, val: T) -> Result<Self> {
-        let path = path.into();
-        let root = toml::from_str(val.as_ref())?;
+    pub fn amend<P: Into<Path>, T: AsRef<str>>(self, path: P, val: T) -> Result<Self> {
+        self.amend_strategy(path.into(), val.as_ref(), None)
+    }
+
+    /// Merge a value at `path` with `strategy`
+    ///
+    /// ```ignore
+    /// let main = Main::from_file("conf/main.toml")?
+    ///     .amend_with("", "callbacks = [\"checkpoint\"]", Strategy::Append)?;
+    /// ```
+    pub fn amend_with<P: Into<Path>, T: AsRef<str>>(self, path: P, val: T, strategy: Strategy) -> Result<Self> {
+        self.amend_strategy(path.into(), val.as_ref(), Some(strategy))
+    }
 
-        self.actions.push(Action::Set(path, root));
+    fn amend_strategy(mut self, path: Path, val: &str, strategy: Option<Strategy>) -> Result<Self> {
+        let root = toml::from_str(val)?;
+
+        let source = Source::new(Origin::Amend, path.to_string());
+        self.actions.push((Action::merge(path, root, strategy), Rc::new(source)));
 
         Ok(self)
     }
+
+    /// Origins of the value at `key`, or of all values below it, after `apply`
+    ///
+    /// Every leaf is listed with the chain of origins that wrote it, oldest
+    /// first, e.g. the line in the main file, an included file and finally a
+    /// command line override. Keys of array elements are their index, like
+    /// `layers.0.units`, negative indices like `layers[-1]` are resolved.
+    pub fn explain(&self, key: &str) -> Vec<(String, Vec<Origin>)> {
+        let key = Path::from(key).absolute(&self.root).to_string();
+        self.context.provenance.explain(&key)
+    }
+
+    /// Whether `--plan` was given, `apply` then fails with the plan
+    pub fn plan_requested(&self) -> bool {
+        self.plan_only
+    }
+
+    /// Key given with `--explain`, the caller prints [`format_explain`](Self::format_explain) for it after `apply`
+    pub fn explain_requested(&self) -> Option<&str> {
+        self.explain.as_deref()
+    }
+
+    /// Format [`explain`](Self::explain) with the values and one origin per line
+    pub fn format_explain(&self, key: &str) -> String {
+        let chains = self.explain(key);
+        if chains.is_empty() {
+            return format!("{} is not set\n", key);
+        }
+
+        let mut out = String::new();
+        for (leaf, chain) in chains {
+            let value = Path::from(leaf.as_str()).get(&self.root)
+                .map(|x| x.to_string())
+                .unwrap_or_default();
+
+            out.push_str(&format!("{} = {}\n", leaf, value));
+            for origin in chain {
+                out.push_str(&format!("    {}\n", origin));
+            }
+        }
+
+        out
+    }
     
     pub fn root(self) -> toml::Value {
         self.root
@@ -366,4 +1371,523 @@ path = 'data/cifar10/'
 "#;
         let builder: TomlBuilder = content.try_into().unwrap();
     }
+
+    #[test]
+    fn test_parse_arg() {
+        let try_parse = |arg: &str| parse_arg(arg).and_then(|(key, val)| Ok((parse_key(key, arg)?.0, val)));
+        let parse = |arg: &str| try_parse(arg).unwrap();
+
+        assert_eq!(parse("experiment.model.from_file.name=gru"),
+            (vec!["experiment".into(), "model".into(), "from_file".into(), "name".into()], Value::String("gru".into())));
+        assert_eq!(parse("name=\"gru\"").1, Value::String("gru".into()));
+        assert_eq!(parse("lr=1e-3").1, Value::Float(1e-3));
+        assert_eq!(parse("path=/data/a=b").1, Value::String("/data/a=b".into()));
+        assert_eq!(parse("'a.b'.\"c=d\" = [1, 2]"), (vec!["a.b".into(), "c=d".into()], Value::Array(vec![1.into(), 2.into()])));
+        assert!(parse("mix = { low = 10 }").1.is_table());
+
+        assert_eq!(parse("layers[2].units=8").0, vec!["layers".to_string(), "2".into(), "units".into()]);
+        assert_eq!(parse("a[0][-1]=8").0, vec!["a".to_string(), "0".into(), "-1".into()]);
+
+        assert!(matches!(try_parse("seed"), Err(Error::InvalidArg(_))));
+        assert!(matches!(try_parse("a..b=1"), Err(Error::InvalidPath(_))));
+        assert!(matches!(try_parse("'a.b=1"), Err(Error::InvalidArg(_))));
+        assert!(matches!(try_parse("a[x]=1"), Err(Error::InvalidPath(_))));
+    }
+
+    /// Create an empty scratch directory for configuration files
+    fn config_dir(name: &str) -> path::PathBuf {
+        let dir = env::temp_dir().join("tsap_tests").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    /// Queue a modification like a command line argument
+    fn modify(builder: &mut TomlBuilder, path: &str, val: Value) {
+        let arg = format!("{}={}", path, val);
+        let source = Source::new(Origin::Arg { index: 1, arg }, path.into());
+        builder.actions.push((Action::Modify(path.into(), val), Rc::new(source)));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = config_dir("cycle");
+        std::fs::write(dir.join("a.toml"), format!(
+            "[model]\nfrom_file = {{ base_path = '{}', name = 'b' }}", dir.display())).unwrap();
+        std::fs::write(dir.join("b.toml"), format!(
+            "[inner]\nfrom_file = {{ base_path = '{}', name = 'a' }}", dir.display())).unwrap();
+
+        let mut builder = TomlBuilder::from_file(dir.join("a.toml")).unwrap();
+        match builder.apply() {
+            Err(Error::IncludeCycle(chain)) => {
+                assert!(chain.ends_with("a.toml"));
+                assert_eq!(chain.matches(" -> ").count(), 2);
+            },
+            x => panic!("expected include cycle, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_include_again() {
+        let dir = config_dir("include_again");
+        std::fs::write(dir.join("b.toml"), "units = 64").unwrap();
+        let include = format!("{{ from_file = {{ base_path = '{}', name = 'b' }} }}", dir.display());
+        std::fs::write(dir.join("a.toml"), format!("model = {}", include)).unwrap();
+
+        let mut builder = TomlBuilder::from_file(dir.join("a.toml")).unwrap();
+        builder.apply().unwrap();
+
+        // including the same file at the same key in a later apply is no cycle
+        builder = builder.amend("", format!("model = {}", include)).unwrap();
+        builder.apply().unwrap();
+
+        assert_eq!(builder.root["model"]["units"].as_integer(), Some(64));
+    }
+
+    #[test]
+    fn test_max_rounds() {
+        let mut builder: TomlBuilder = "path = { glob = '*.none' }".try_into().unwrap();
+        builder = builder.max_rounds(0);
+
+        assert!(matches!(builder.apply(), Err(Error::ResolutionLimit(0))));
+    }
+
+    #[test]
+    fn test_interpolate_after_overrides() {
+        let mut builder: TomlBuilder = "batch_size = 32\neval_batch = '${batch_size}'".try_into().unwrap();
+        builder = builder.amend("", "batch_size = 64").unwrap();
+        builder.apply().unwrap();
+
+        assert_eq!(builder.root["eval_batch"].as_integer(), Some(64));
+    }
+
+    #[test]
+    fn test_escaped_keys() {
+        let mut builder: TomlBuilder = "label = '${tool.cmd}'\n[tool]\n'@@cmd' = 'make'\n[build]\n'@@env' = 'dev'".try_into().unwrap();
+        builder = builder.amend_args_from(["tool.cmd=pwd"]).unwrap();
+        builder = builder.amend("", "build = { env = 'prod' }").unwrap();
+        builder.apply().unwrap();
+
+        assert_eq!(builder.root["tool"]["cmd"].as_str(), Some("pwd"));
+        assert_eq!(builder.root["label"].as_str(), Some("pwd"));
+        assert_eq!(builder.root["build"]["env"].as_str(), Some("prod"));
+    }
+
+    #[test]
+    fn test_templates_in_arrays() {
+        let dir = config_dir("arrays");
+        std::fs::write(dir.join("dense.toml"), "units = 64").unwrap();
+        std::fs::write(dir.join("main.toml"), format!(r#"
+            layers = [
+                {{ from_file = {{ base_path = '{0}', name = 'dense' }} }},
+                {{ from_file = {{ base_path = '{0}', name = 'dense' }} }},
+            ]
+            nested = [[{{ env = 'TSAP_TEST_UNSET', default = 1 }}]]
+        "#, dir.display())).unwrap();
+
+        let mut builder = TomlBuilder::from_file(dir.join("main.toml")).unwrap();
+        modify(&mut builder, "layers.1.units", Value::Integer(128));
+        builder.apply().unwrap();
+
+        assert_eq!(builder.root["layers"][0]["units"].as_integer(), Some(64));
+        assert_eq!(builder.root["layers"][1]["units"].as_integer(), Some(128));
+        assert_eq!(builder.root["nested"][0][0].as_integer(), Some(1));
+    }
+
+    #[test]
+    fn test_conditional_after_overrides() {
+        let dir = config_dir("conditional");
+        std::fs::write(dir.join("debug.toml"), "workers = 1").unwrap();
+        std::fs::write(dir.join("main.toml"), format!(r#"
+            debug = false
+            [loader]
+            if = "${{debug}}"
+            then = {{ from_file = {{ base_path = '{}', name = 'debug' }} }}
+            else = {{ workers = 16 }}
+        "#, dir.display())).unwrap();
+
+        let mut builder = TomlBuilder::from_file(dir.join("main.toml")).unwrap();
+        builder.apply().unwrap();
+        assert_eq!(builder.root["loader"]["workers"].as_integer(), Some(16));
+
+        let mut builder = TomlBuilder::from_file(dir.join("main.toml")).unwrap()
+            .amend("", "debug = true").unwrap();
+        modify(&mut builder, "loader.workers", Value::Integer(2));
+        builder.apply().unwrap();
+        assert_eq!(builder.root["loader"]["workers"].as_integer(), Some(2));
+    }
+
+    #[test]
+    fn test_explain() {
+        let dir = config_dir("explain");
+        std::fs::write(dir.join("gru.toml"), "hidden = 32\nlayers = 2").unwrap();
+        std::fs::write(dir.join("main.toml"), format!(r#"seed = 1
+rev = {{ env = "TSAP_TEST_UNSET", default = "none" }}
+
+[experiment]
+batch_size = 64
+model = {{ from_file = {{ base_path = '{}', name = 'gru' }} }}
+eval_batch = {{ expr = "2 * ${{experiment.batch_size}}" }}
+"#, dir.display())).unwrap();
+
+        let mut builder = TomlBuilder::from_file(dir.join("main.toml")).unwrap()
+            .amend("experiment", "batch_size = 32").unwrap()
+            .amend_args_from(["--explain", "seed"]).unwrap();
+        modify(&mut builder, "experiment.model.hidden", Value::Integer(128));
+        builder.apply().unwrap();
+        assert_eq!(builder.explain_requested(), Some("seed"));
+        assert!(builder.is_applied());
+
+        let main = dir.join("main.toml");
+        let file = |path: &path::Path, line| Origin::File { path: path.to_path_buf(), line: Some(line) };
+        let template = |name: &str| Origin::Template { name: name.into() };
+
+        assert_eq!(builder.explain("seed"), vec![("seed".into(), vec![file(&main, 1)])]);
+        assert!(builder.format_explain("seed").starts_with("seed = 1\n"));
+        assert_eq!(builder.explain("rev")[0].1, vec![file(&main, 2), template("env")]);
+        assert_eq!(builder.explain("experiment.batch_size")[0].1, vec![file(&main, 5), Origin::Amend]);
+        assert_eq!(builder.explain("experiment.eval_batch")[0].1, vec![file(&main, 7), template("expr")]);
+
+        let model = builder.explain("experiment.model");
+        assert_eq!(model.len(), 2);
+        assert_eq!(model[0].1, vec![
+            file(&main, 6),
+            template("from_file"),
+            file(&dir.join("gru.toml"), 1),
+            Origin::Arg { index: 1, arg: "experiment.model.hidden=128".into() },
+        ]);
+        assert_eq!(model[1].1.last(), Some(&file(&dir.join("gru.toml"), 2)));
+    }
+
+    #[test]
+    fn test_run_dir() {
+        let dir = config_dir("run_dir");
+        let pattern = format!("{}/${{variant}}/%Y", dir.display());
+
+        let content = "variant = 'train'\nrev = { env = 'TSAP_TEST_UNSET', default = 'none' }\nliteral = '\\${variant}'\n[tool]\n'@@cmd' = 'make'";
+        let mut builder = TomlBuilder::try_from(content).unwrap()
+            .run_dir(RunDir::new(&pattern).key("paths.output"));
+        builder.apply().unwrap();
+
+        let path = builder.run_path().unwrap().to_path_buf();
+        assert!(path.starts_with(dir.join("train")));
+        assert_eq!(builder.root["paths"]["output"].as_str(), Some(path.to_str().unwrap()));
+
+        let templates: Value = std::fs::read_to_string(path.join("templates.toml")).unwrap().parse().unwrap();
+        assert_eq!(templates["templates"][0]["key"].as_str(), Some("rev"));
+        assert_eq!(templates["templates"][0]["value"].as_str(), Some("none"));
+        assert!(path.join("overrides.txt").is_file());
+
+        // the saved configuration reproduces the run
+        let mut replay = TomlBuilder::from_file(path.join("config.toml")).unwrap();
+        replay.apply().unwrap();
+        assert_eq!(replay.root, builder.root);
+        assert_eq!(replay.root["tool"]["cmd"].as_str(), Some("make"));
+        assert_eq!(replay.root["literal"].as_str(), Some("${variant}"));
+
+        // a second run in the same period gets its own directory
+        let mut builder = TomlBuilder::try_from(content).unwrap().run_dir(RunDir::new(&pattern));
+        builder.apply().unwrap();
+        assert_ne!(builder.run_path(), Some(path.as_path()));
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = config_dir("replay");
+        let lock = dir.join("config.lock");
+
+        env::set_var("TSAP_TEST_REPLAY", "first");
+        let content = "data = { env = 'TSAP_TEST_REPLAY' }\nstarted = { now = '%s' }";
+        let mut builder = TomlBuilder::try_from(content).unwrap().lock_file(&lock);
+        builder.apply().unwrap();
+
+        // recorded values are used even though the environment changed
+        env::set_var("TSAP_TEST_REPLAY", "second");
+        let mut replay = TomlBuilder::try_from(content).unwrap().replay(&lock).unwrap();
+        replay.apply().unwrap();
+        assert_eq!(replay.root, builder.root);
+        assert_eq!(replay.root["data"].as_str(), Some("first"));
+        assert!(replay.mismatches().is_empty());
+
+        // verifying evaluates the templates again, except `now`
+        let mut verify = TomlBuilder::try_from(content).unwrap().replay(&lock).unwrap().verify_replay();
+        verify.apply().unwrap();
+        assert_eq!(verify.root["data"].as_str(), Some("first"));
+        assert_eq!(verify.mismatches().len(), 1);
+        assert_eq!(verify.mismatches()[0].key, "data");
+        assert_eq!(verify.mismatches()[0].live, Ok(Value::String("second".into())));
+
+        let mut changed = TomlBuilder::try_from("data = { env = 'TSAP_TEST_REPLAY', default = 'x' }")
+            .unwrap().replay(&lock).unwrap();
+        assert!(matches!(changed.apply(), Err(Error::MissingLockEntry(..))));
+    }
+
+    #[test]
+    fn test_plan() {
+        let dir = config_dir("plan");
+        std::fs::write(dir.join("gru.toml"), "hidden = 32").unwrap();
+        std::fs::write(dir.join("main.toml"), format!(
+            "seed = 1\nmodel = {{ from_file = {{ base_path = '{}', name = 'gru' }} }}", dir.display())).unwrap();
+
+        let mut builder = TomlBuilder::from_file(dir.join("main.toml")).unwrap()
+            .amend("", "seed = 2").unwrap()
+            .amend_args_from(["--plan"]).unwrap();
+        modify(&mut builder, "model.hidden", Value::Integer(128));
+        assert!(builder.plan_requested());
+
+        let plan = builder.plan().unwrap();
+        let steps = plan.steps();
+        assert_eq!(steps.len(), 4);
+        assert!(matches!(&steps[0], Step::Apply { action, .. } if action == "set { seed = 2 }"));
+        assert!(matches!(&steps[1], Step::Defer { action, .. } if action == "modify model.hidden = 128"));
+        assert!(matches!(&steps[2], Step::Expand { key, template, .. } if key == "model" && template == "from_file"));
+        assert!(matches!(&steps[3], Step::Apply { action, .. } if action == "modify model.hidden = 128"));
+
+        // planning leaves the builder untouched, with `--plan` applying returns the plan
+        match builder.apply() {
+            Err(Error::PlanRequested(plan)) => assert_eq!(plan.steps().len(), 4),
+            x => panic!("expected plan, got {:?}", x),
+        }
+        assert!(builder.root["model"].get("from_file").is_some());
+        builder.plan_only = false;
+        builder.apply().unwrap();
+        assert_eq!(builder.root["model"]["hidden"].as_integer(), Some(128));
+    }
+
+    #[cfg(feature = "cmd")]
+    #[test]
+    fn test_plan_cmd() {
+        let mut builder: TomlBuilder = "fail = { cmd = 'exit 1', shell = 'sh' }".try_into().unwrap();
+
+        let plan = builder.plan().unwrap();
+        assert!(matches!(&plan.steps()[0], Step::Expand { template, .. } if template == "cmd"));
+        assert!(builder.apply().is_err());
+    }
+
+    #[test]
+    fn test_amend_args_from() {
+        let dir = config_dir("args");
+        let args_file = dir.join("sweep.args");
+        std::fs::write(&args_file, "# learning rate sweep\nlr=0.1\n\nname=gru").unwrap();
+
+        let mut builder = TomlBuilder::try_from("seed = 1\nlr = 0.5\nname = 'x'").unwrap()
+            .amend_args_from(vec!["seed=2".to_string(), format!("@{}", args_file.display()), "--".into(), "seed=3".into()])
+            .unwrap();
+        builder.apply().unwrap();
+
+        assert_eq!(builder.root["seed"].as_integer(), Some(2));
+        assert_eq!(builder.root["lr"].as_float(), Some(0.1));
+        assert_eq!(builder.root["name"].as_str(), Some("gru"));
+        assert_eq!(builder.passthrough(), ["seed=3"]);
+        assert_eq!(builder.explain("name")[0].1.last(), Some(&Origin::File { path: args_file.clone(), line: Some(4) }));
+
+        std::fs::write(&args_file, format!("@{}", args_file.display())).unwrap();
+        let res = TomlBuilder::try_from("seed = 1").unwrap().amend_args_from([format!("@{}", args_file.display())]);
+        assert!(matches!(res, Err(Error::IncludeCycle(_))));
+    }
+
+    #[test]
+    fn test_override_grammar() {
+        let content = "seed = 1\nlr = 0.5\n[model]\nhidden = 32";
+        let builder = || TomlBuilder::try_from(content).unwrap();
+
+        let mut ok = builder().amend_args_from(["seed=2", "+model.dropout=0.1", "++lr=0.1", "++epochs=10", "~model.hidden"]).unwrap();
+        ok.apply().unwrap();
+        assert_eq!(ok.root["seed"].as_integer(), Some(2));
+        assert_eq!(ok.root["model"]["dropout"].as_float(), Some(0.1));
+        assert_eq!(ok.root["lr"].as_float(), Some(0.1));
+        assert_eq!(ok.root["epochs"].as_integer(), Some(10));
+        assert!(ok.root["model"].get("hidden").is_none());
+
+        let fails = |arg: &str, reason: &str| {
+            match builder().amend_args_from([arg]).unwrap().apply() {
+                Err(Error::InvalidOverride(_, msg)) => assert!(msg.contains(reason), "{}", msg),
+                x => panic!("expected invalid override for {}, got {:?}", arg, x),
+            }
+        };
+
+        fails("epochs=10", "use `+epochs=...` to add it");
+        fails("+seed=2", "use `++seed=...` to override it");
+        fails("+optim.lr=2", "parent of `optim.lr` does not exist");
+        fails("~model.layers", "`model.layers` does not exist");
+        assert!(matches!(builder().amend_args_from(["~"]), Err(Error::InvalidArg(_))));
+    }
+
+    #[test]
+    fn test_override_template() {
+        let dir = config_dir("override_template");
+        std::fs::write(dir.join("gru.toml"), "hidden = 32").unwrap();
+        let content = format!("[model]\nfrom_file = {{ base_path = '{}', name = 'gru' }}", dir.display());
+
+        // new keys wait until the template replaced the table
+        let mut ok = TomlBuilder::try_from(content.as_str()).unwrap()
+            .amend_args_from(["+model.dropout=0.1", "++model.layers=2"]).unwrap();
+        ok.apply().unwrap();
+        assert_eq!(ok.root["model"]["dropout"].as_float(), Some(0.1));
+        assert_eq!(ok.root["model"]["layers"].as_integer(), Some(2));
+        assert_eq!(ok.root["model"]["hidden"].as_integer(), Some(32));
+
+        let mut fails = TomlBuilder::try_from(content.as_str()).unwrap()
+            .amend_args_from(["+model.hidden=64"]).unwrap();
+        match fails.apply() {
+            Err(Error::InvalidOverride(_, msg)) => assert!(msg.contains("use `++model.hidden=...` to override it"), "{}", msg),
+            x => panic!("expected invalid override, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_array_overrides() {
+        let content = "[[layers]]\nunits = 16\n[[layers]]\nunits = 32\n[[layers]]\nunits = 64";
+        let builder = || TomlBuilder::try_from(content).unwrap();
+        let units = |b: &TomlBuilder| b.root["layers"].as_array().unwrap().iter()
+            .map(|x| x["units"].as_integer().unwrap())
+            .collect::<Vec<_>>();
+
+        let mut ok = builder().amend_args_from(["layers[1].units=128", "layers[-1].units=8"]).unwrap();
+        ok.apply().unwrap();
+        assert_eq!(units(&ok), [16, 128, 8]);
+        assert_eq!(ok.explain("layers[-1].units")[0].0, "layers.2.units");
+
+        let mut ok = builder().amend_args_from(["layers+=[{ units = 1 }, { units = 2 }]", "+layers[0]={ units = 4 }", "~layers[-2]", "~layers.1"]).unwrap();
+        ok.apply().unwrap();
+        assert_eq!(units(&ok), [4, 32, 64, 2]);
+        assert!(matches!(ok.explain("layers.1.units")[0].1.last(), Some(Origin::File { .. }) | Some(Origin::Amend)));
+        assert!(matches!(ok.explain("layers.3.units")[0].1.last(), Some(Origin::Arg { index: 1, .. })));
+        assert!(matches!(ok.explain("layers.0.units")[0].1.last(), Some(Origin::Arg { index: 2, .. })));
+
+        let fails = |arg: &str, reason: &str| {
+            match builder().amend_args_from([arg]).unwrap().apply() {
+                Err(Error::InvalidOverride(_, msg)) => assert!(msg.contains(reason), "{}", msg),
+                x => panic!("expected invalid override for {}, got {:?}", arg, x),
+            }
+        };
+
+        fails("layers[3].units=1", "`layers.3.units` does not exist");
+        fails("~layers[-4]", "index out of range, `layers` has 3 elements");
+        fails("+layers[0].units[0]=1", "`layers.0.units` is not an array");
+        fails("layers[0].units+=1", "`layers.0.units` is not an array");
+    }
+
+    #[test]
+    fn test_merge_strategies() {
+        let content = "callbacks = ['log']\ntags = ['a', 'b']\n[[layers]]\nname = 'conv'\nunits = 8\n[[layers]]\nname = 'fc'\nunits = 4";
+        let builder = || TomlBuilder::try_from(content).unwrap();
+        let names = |b: &TomlBuilder, key: &str| b.root[key].as_array().unwrap().iter()
+            .map(|x| x.as_str().map(String::from).unwrap_or_else(|| x["units"].to_string()))
+            .collect::<Vec<_>>();
+
+        let mut b = builder()
+            .amend_with("", "callbacks = ['checkpoint']", Strategy::Append).unwrap()
+            .amend_with("", "tags = ['b', 'c']", Strategy::Union).unwrap()
+            .amend_with("", "layers = [{ name = 'fc', units = 16 }, { name = 'out', units = 2 }]", "merge_by = 'name'".parse().unwrap()).unwrap();
+        b.apply().unwrap();
+        assert_eq!(names(&b, "callbacks"), ["log", "checkpoint"]);
+        assert_eq!(names(&b, "tags"), ["a", "b", "c"]);
+        assert_eq!(names(&b, "layers"), ["8", "16", "2"]);
+        assert!(matches!(b.explain("layers.2.units")[0].1.last(), Some(Origin::Amend)));
+
+        let mut b = builder()
+            .merge_strategy("callbacks", Strategy::Prepend)
+            .amend_args_from(["callbacks=['early_stop']", "--merge", "layers=deep", "layers=[{ units = 1 }]"]).unwrap();
+        b.apply().unwrap();
+        assert_eq!(names(&b, "callbacks"), ["early_stop", "log"]);
+        assert!(matches!(b.explain("callbacks.0")[0].1.as_slice(), [Origin::Arg { index: 1, .. }]));
+        assert_eq!(b.explain("callbacks.1")[0].1, [Origin::Amend]);
+        assert_eq!(names(&b, "layers"), ["1", "4"]);
+        assert_eq!(b.root["layers"][0]["name"].as_str(), Some("conv"));
+
+        let mut b = builder().merge_strategy("", Strategy::Replace)
+            .amend("", "layers = [{ name = 'x' }]").unwrap();
+        b.apply().unwrap();
+        assert!(b.root["layers"][0].get("units").is_none());
+
+        // overriding with the same value is recorded as well
+        let mut b = builder().amend_args_from(["--merge", "tags=union", "tags=['a', 'b']"]).unwrap();
+        b.apply().unwrap();
+        assert_eq!(names(&b, "tags"), ["a", "b"]);
+        assert_eq!(b.explain("tags.0")[0].1, [Origin::Amend]);
+        let mut b = builder().amend_args_from(["tags=['a', 'b']"]).unwrap();
+        b.apply().unwrap();
+        assert!(matches!(b.explain("tags.0")[0].1.as_slice(), [Origin::Amend, Origin::Arg { .. }]));
+
+        assert!(matches!("concat".parse::<Strategy>(), Err(Error::InvalidStrategy(_))));
+        assert!(matches!(builder().amend_args_from(["--merge=tags=merge_by="]), Err(Error::InvalidStrategy(_))));
+    }
+
+    #[test]
+    fn test_coercion() {
+        let content = "lr = 0.5\nepochs = 10\nstart = 2024-01-01\nname = 'x'\nmodel = { cmd = 'echo gru' }";
+        let builder = || TomlBuilder::try_from(content).unwrap();
+
+        let mut b = builder().amend_args_from(["lr=1", "epochs='20'", "start='2024-02-01T10:00:00'"]).unwrap();
+        b.apply_actions().unwrap();
+        assert_eq!(b.root["lr"].as_float(), Some(1.0));
+        assert_eq!(b.root["epochs"].as_integer(), Some(20));
+        assert_eq!(b.root["start"].to_string(), "2024-02-01T10:00:00");
+
+        let mut b = builder().coercion(Coercion::none()).amend_args_from(["lr=1"]).unwrap();
+        assert!(b.apply_actions().unwrap());
+
+        let mut b = builder().coercion(Coercion::default().strict()).amend_args_from(["model='lstm'", "name=1"]).unwrap();
+        match b.apply_actions() {
+            Err(Error::TypeMismatch(key, "string", "integer")) => assert_eq!(key, "name"),
+            x => panic!("expected type mismatch, got {:?}", x),
+        }
+
+        // a failed merge leaves the configuration and the pending actions unchanged
+        let mut b = builder().coercion(Coercion::default().strict())
+            .amend("", "lr = 0.1\nname = 1").unwrap()
+            .amend_args_from(["epochs=20"]).unwrap();
+        assert!(matches!(b.apply_actions(), Err(Error::TypeMismatch(..))));
+        assert_eq!(b.root["lr"].as_float(), Some(0.5));
+        assert_eq!(b.root["epochs"].as_integer(), Some(10));
+        assert_eq!(b.actions.len(), 2);
+
+        // references are converted after interpolation
+        let typed = || TomlBuilder::try_from("lr = 0.5\nepochs = 10\nsteps = 3").unwrap().coercion(Coercion::default().strict());
+        let mut b = typed().amend_args_from(["epochs=${steps}", "lr=${steps}"]).unwrap();
+        b.apply().unwrap();
+        assert_eq!(b.root["epochs"].as_integer(), Some(3));
+        assert_eq!(b.root["lr"].as_float(), Some(3.0));
+        match typed().amend_args_from(["epochs=${lr}"]).unwrap().apply() {
+            Err(Error::TypeMismatch(key, "integer", "float")) => assert_eq!(key, "epochs"),
+            x => panic!("expected type mismatch, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_merge_diagnostics() {
+        let mut builder = TomlBuilder::try_from("lr = 0.5\n[experiment]\nbatch_size = 64\nname = 'x'").unwrap()
+            .amend_args_from(["experiment.batchsize=32", "lr=fast", "experiment.name.first=a"]).unwrap();
+
+        let unapplied = match builder.apply() {
+            Err(Error::MergeFailed(x)) => x,
+            x => panic!("expected failed merge, got {:?}", x),
+        };
+        assert_eq!(unapplied.len(), 3);
+
+        assert_eq!(unapplied[0].path, "experiment.batchsize");
+        assert_eq!(unapplied[0].value.as_deref(), Some("32"));
+        assert_eq!(unapplied[0].suggestion.as_deref(), Some("experiment.batch_size"));
+        assert_eq!(unapplied[0].to_string(),
+            "modify experiment.batchsize = 32 (argument 1 `experiment.batchsize=32`): `experiment.batchsize` does not exist, \
+            use `+experiment.batchsize=...` to add it, did you mean `experiment.batch_size`?");
+
+        assert_eq!(unapplied[1].reason, "can not override float with string");
+        assert_eq!(unapplied[1].existing, Some(("float".into(), "0.5".into())));
+
+        assert_eq!(unapplied[2].reason, "the parent `experiment.name` is string \"x\", not a table");
+        assert!(Error::MergeFailed(unapplied).to_string().starts_with("could not apply overrides\n  modify experiment.batchsize"));
+
+        // a single override gives the same details
+        let mut builder = TomlBuilder::try_from("lr = 0.5").unwrap().amend_args_from(["lr=fast"]).unwrap();
+        match builder.apply() {
+            Err(Error::InvalidOverride(action, msg)) => {
+                assert_eq!(action, "modify lr = \"fast\" (argument 1 `lr=fast`)");
+                assert_eq!(msg, "can not override float with string, found float 0.5");
+            },
+            x => panic!("expected invalid override, got {:?}", x),
+        }
+    }
 }
@@ -58,7 +58,9 @@ impl Intermediate {
                 type Error = <#item2 as ParamGuard>::Error;
 
                 fn try_from(mut val: #builder_name) -> Result<#item2, Self::Error> {
-                    val.0.apply()?;
+                    if !val.0.is_applied() {
+                        val.0.apply()?;
+                    }
 
                     let obj: #item2 = val.0.root().try_into()
                         .map_err(|x| tsap::Error::TomlParse(x))?;
@@ -110,11 +112,125 @@ impl Intermediate {
                     Ok(self)
                 }
 
+                pub fn amend_file_with<T: AsRef<std::path::Path>>(mut self, path: T, strategy: tsap::Strategy) -> Result<#builder_name, <#item2 as ParamGuard>::Error> {
+                    self.0 = self.0.amend_file_with(path, strategy)?;
+
+                    Ok(self)
+                }
+
+                pub fn amend_with<P: Into<tsap::Path>, T: AsRef<str>>(mut self, path: P, val: T, strategy: tsap::Strategy) -> Result<Self, <#item2 as ParamGuard>::Error> {
+                    self.0 = self.0.amend_with(path, val, strategy)?;
+
+                    Ok(self)
+                }
+
+                pub fn merge_strategy<P: Into<tsap::Path>>(mut self, path: P, strategy: tsap::Strategy) -> Self {
+                    self.0 = self.0.merge_strategy(path, strategy);
+
+                    self
+                }
+
+                pub fn coercion(mut self, coercion: tsap::Coercion) -> Self {
+                    self.0 = self.0.coercion(coercion);
+
+                    self
+                }
+
+                pub fn max_rounds(mut self, max_rounds: usize) -> Self {
+                    self.0 = self.0.max_rounds(max_rounds);
+
+                    self
+                }
+
+                pub fn policy(mut self, policy: tsap::Policy) -> Self {
+                    self.0 = self.0.policy(policy);
+
+                    self
+                }
+
+                pub fn template_keys(mut self, keys: tsap::TemplateKeys) -> Self {
+                    self.0 = self.0.template_keys(keys);
+
+                    self
+                }
+
+                pub fn run_seed(mut self, seed: u64) -> Self {
+                    self.0 = self.0.run_seed(seed);
+
+                    self
+                }
+
+                pub fn run_dir(mut self, run_dir: tsap::RunDir) -> Self {
+                    self.0 = self.0.run_dir(run_dir);
+
+                    self
+                }
+
+                pub fn lock_file<T: AsRef<std::path::Path>>(mut self, path: T) -> Self {
+                    self.0 = self.0.lock_file(path);
+
+                    self
+                }
+
+                pub fn replay<T: AsRef<std::path::Path>>(mut self, path: T) -> Result<#builder_name, <#item2 as ParamGuard>::Error> {
+                    self.0 = self.0.replay(path)?;
+
+                    Ok(self)
+                }
+
+                pub fn verify_replay(mut self) -> #builder_name {
+                    self.0 = self.0.verify_replay();
+
+                    self
+                }
+
+                pub fn plan(&mut self) -> Result<tsap::Plan, <#item2 as ParamGuard>::Error> {
+                    self.0.plan().map_err(|x| x.into())
+                }
+
                 pub fn amend_args(mut self) -> Result<#builder_name, <#item2 as ParamGuard>::Error> {
                     self.0 = self.0.amend_args()?;
 
                     Ok(self)
                 }
+
+                pub fn amend_args_from<I, T>(mut self, args: I) -> Result<#builder_name, <#item2 as ParamGuard>::Error>
+                where
+                    I: IntoIterator<Item = T>,
+                    T: AsRef<str>,
+                {
+                    self.0 = self.0.amend_args_from(args)?;
+
+                    Ok(self)
+                }
+
+                pub fn passthrough(&self) -> &[String] {
+                    self.0.passthrough()
+                }
+
+                pub fn apply(&mut self) -> Result<(), <#item2 as ParamGuard>::Error> {
+                    self.0.apply().map_err(|x| x.into())
+                }
+
+                pub fn mismatches(&self) -> &[tsap::Mismatch] {
+                    self.0.mismatches()
+                }
+
+                pub fn explain(&self, key: &str) -> Vec<(String, Vec<tsap::Origin>)> {
+                    self.0.explain(key)
+                }
+
+                pub fn explain_requested(&self) -> Option<&str> {
+                    self.0.explain_requested()
+                }
+
+                pub fn format_explain(&self, key: &str) -> String {
+                    self.0.format_explain(key)
+                }
+
+                pub fn run_path(&self) -> Option<&std::path::Path> {
+                    self.0.run_path()
+                }
             }
 
             impl #builder_name {